@@ -18,6 +18,7 @@
 //! // to add a string annotation to your errors.
 //! // You may add a custom error message where `{0}` 
 //! // is your original error variant.
+//! // The attribute must be placed above `#[derive(Error)]`.
 //! #[string_context("Custom context message: {0}")]
 //! #[derive(Error,Debug)]
 //! enum MyError {
@@ -74,19 +75,1274 @@
 //!     }
 //! }
 //! ```
+//! Exhaustive matching is available with the `kind` and `parts` options or with the [match_peeled!] macro, and each variant gets the `is_<variant>()` predicate ignoring the context.
+//!
+//! # Other features
+//! Besides `with_context()`, [AddErrorContext] provides eager, static, conditional and other variants of it, while [AddOptionContext], [AddMappedErrorContext], [AddIteratorContext] and [AddBoxedErrorContext] annotate missing values, unconvertible errors, items of iterators and boxed errors. The `#[err_context]` attribute annotates all errors returned from a function, and [scoped_context!] adds the contexts of the enclosing scopes. The options of the generated code are listed in the docs of [string_context] and the optional integrations are enabled by the cargo features `termination`, `fields`, `severity`, `thread-name`, `async`, `streams`, `tracing`, `serde`, `miette`, `anyhow` and `testing`. All of them are described in more detail in the README.
+
+// The generated code refers to the items of this crate by absolute paths
+extern crate self as thiserror_string_context;
+
+pub use thiserror_string_context_macro::{string_context, match_peeled, err_context};
+
+#[cfg(feature = "anyhow")]
+#[doc(hidden)]
+pub use anyhow as __anyhow;
+
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub use tracing as __tracing;
+
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+pub use serde as __serde;
+
+// Converts the context returned by a closure into the stored string.
+// Owned strings, like the ones made by `format!()`, are moved instead of copied,
+// while other values are formatted with `Display`. There is no specialization on stable,
+// so the type is recognized by its `TypeId`, which doesn't depend on the lifetimes.
+#[doc(hidden)]
+pub fn __into_context<S: std::fmt::Display>(ctx: S) -> String {
+    use std::borrow::Cow;
+    use std::mem::ManuallyDrop;
+    if type_id_of::<S>() == std::any::TypeId::of::<String>() {
+        let ctx = ManuallyDrop::new(ctx);
+        // SAFETY: `S` is `String`, which has no lifetimes, and `ctx` is not dropped
+        unsafe { std::ptr::read(&*ctx as *const S as *const String) }
+    } else if type_id_of::<S>() == std::any::TypeId::of::<Cow<'static, str>>() {
+        let ctx = ManuallyDrop::new(ctx);
+        // SAFETY: `S` is `Cow<'_, str>` and `ctx` is not dropped. The borrowed string
+        // is copied before returning, so its lifetime doesn't matter
+        unsafe { std::ptr::read(&*ctx as *const S as *const Cow<'_, str>) }.into_owned()
+    } else {
+        ctx.to_string()
+    }
+}
+
+// `TypeId` of a type, which is not necessarily `'static`
+fn type_id_of<T: ?Sized>() -> std::any::TypeId {
+    trait NonStaticAny {
+        fn type_id(&self) -> std::any::TypeId where Self: 'static;
+    }
+
+    impl<T: ?Sized> NonStaticAny for std::marker::PhantomData<T> {
+        fn type_id(&self) -> std::any::TypeId where Self: 'static {
+            std::any::TypeId::of::<T>()
+        }
+    }
+
+    let phantom = std::marker::PhantomData::<T>;
+    // SAFETY: only the lifetime of the trait object is extended, `TypeId` is the same for all lifetimes
+    let any = unsafe { std::mem::transmute::<&dyn NonStaticAny, &(dyn NonStaticAny + 'static)>(&phantom) };
+    any.type_id()
+}
+
+/// Extension trait for adding context to `Result`s. Implemented by `#[string_context]` for each annotated enum.
+///
+/// Only `with_context()` and `into_target()` have to be implemented by hand, the other methods are provided on top of them.
+/// The methods, which need to look at the converted error, require it to implement [ContextLayers].
+/// The impls generated by the macro replace all methods with direct ones.
+pub trait AddErrorContext<E,T>: ErrorType + Sized {
+    /// Adds the context returned by the closure to the error.
+    /// The context could be anything implementing `Display`,
+    /// it is converted to a string only if there is an error.
+    /// The strings returned by the closure, for example by `format!()`, are stored without copying.
+    fn with_context<S: std::fmt::Display>(self, f: impl FnOnce()->S) -> std::result::Result<T, E>;
+    /// Converts the error into `E` without adding a context.
+    fn into_target(self) -> std::result::Result<T, E>;
+    /// Adds the context returned by the closure and passes the resulting error to `tap`,
+    /// for example to report it, before returning it. Both closures are only called if there is an error.
+    fn with_context_tap<S: std::fmt::Display>(self, f: impl FnOnce()->S, tap: impl FnOnce(&E)) -> std::result::Result<T, E> {
+        self.with_context(f).inspect_err(tap)
+    }
+    /// Adds the context returned by the closure passed as a trait object,
+    /// for example when the closure is chosen at runtime.
+    fn with_context_dyn(self, f: &dyn Fn()->String) -> std::result::Result<T, E> {
+        self.with_context(f)
+    }
+    /// Adds the contexts of the active scopes of the current thread declared with [context_scope].
+    /// It is called by the [scoped_context!] macro, which is the preferred way to use the scopes.
+    fn with_scoped_context(self) -> std::result::Result<T, E>
+    where
+        E: ContextLayers,
+    {
+        self.into_target().map_err(|base| {
+            with_context_scopes(|scopes| scopes.iter().rev().fold(base, |inner, ctx| inner.push_context(ctx.clone())))
+        })
+    }
+    /// Adds the context passed as `format_args!()` to the callback provided to the closure:
+    /// `with_context_args(|w| w(format_args!("row {} col {}", r, c)))`.
+    /// The context is formatted directly into the stored string without intermediate allocations.
+    fn with_context_args(self, f: impl FnOnce(&mut dyn FnMut(std::fmt::Arguments<'_>))) -> std::result::Result<T, E> {
+        self.with_context(|| {
+            let mut ctx = String::new();
+            f(&mut |args| {
+                let _ = std::fmt::Write::write_fmt(&mut ctx, args);
+            });
+            ctx
+        })
+    }
+    /// Adds the OS string as a context to the error. The strings, which are not valid UTF-8,
+    /// are converted lossily with the invalid sequences replaced by `U+FFFD`.
+    fn context_os(self, s: impl AsRef<std::ffi::OsStr>) -> std::result::Result<T, E> {
+        self.with_context(|| s.as_ref().to_string_lossy().into_owned())
+    }
+    /// Adds the bytes as a context to the error converting them lossily in the same way as `context_os()`.
+    fn context_lossy_utf8(self, bytes: impl AsRef<[u8]>) -> std::result::Result<T, E> {
+        self.with_context(|| String::from_utf8_lossy(bytes.as_ref()).into_owned())
+    }
+    /// Adds the path as a context to the error. Paths, which are not valid UTF-8,
+    /// are rendered lossily with the invalid bytes replaced by `U+FFFD`.
+    fn with_path_context(self, path: impl AsRef<std::path::Path>) -> std::result::Result<T, E> {
+        self.with_context(|| path.as_ref().to_string_lossy().into_owned())
+    }
+    /// Adds the context like `reading /etc/app.conf` made of the label followed by the path,
+    /// which is rendered lossily in the same way as in `with_path_context()`.
+    fn with_labeled_path_context(self, label: &str, path: impl AsRef<std::path::Path>) -> std::result::Result<T, E> {
+        self.with_context(|| format!("{} {}", label, path.as_ref().display()))
+    }
+    /// Adds the context computed from the original error, which is converted
+    /// into `E` after the closure returns.
+    fn with_context_with<S: std::fmt::Display>(self, f: impl FnOnce(&Self::Error)->S) -> std::result::Result<T, E>
+    where
+        Self: Into<std::result::Result<T, Self::Error>> + From<std::result::Result<T, Self::Error>>,
+    {
+        match self.into() {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                let ctx = f(&e);
+                Self::from(Err(e)).with_context(|| ctx)
+            }
+        }
+    }
+    /// Passes the original error to the closure, which returns both the base error
+    /// and the context for it. This allows to choose the base variant depending on the original error.
+    fn map_to_context(self, f: impl FnOnce(Self::Error)->(E, String)) -> std::result::Result<T, E>
+    where
+        Self: Into<std::result::Result<T, Self::Error>>,
+        E: ContextLayers,
+    {
+        self.into().map_err(|e| {
+            let (base, ctx) = f(e);
+            base.push_context(ctx)
+        })
+    }
+    /// Adds the given context to the error. Unlike `with_context()` the context
+    /// is converted eagerly, so this is meant for string literals and ready values.
+    fn context(self, msg: impl Into<String>) -> std::result::Result<T, E> {
+        let msg = msg.into();
+        self.with_context(|| msg)
+    }
+    /// Adds the static string as a context to the error. The string is stored
+    /// without copying, so no allocations are made besides boxing the error.
+    fn with_static_context(self, msg: &'static str) -> std::result::Result<T, E> {
+        self.with_context(|| msg)
+    }
+    /// Adds the context only if the condition is true, otherwise the error is converted
+    /// without adding a context and the closure is not called.
+    fn with_context_if<S: std::fmt::Display>(self, cond: bool, f: impl FnOnce()->S) -> std::result::Result<T, E> {
+        if cond {
+            self.with_context(f)
+        } else {
+            self.into_target()
+        }
+    }
+    /// Adds the context returned by the closure only if the error doesn't have any context yet.
+    /// The closure is not called if the context is already present.
+    fn or_else_context<S: std::fmt::Display>(self, f: impl FnOnce()->S) -> std::result::Result<T, E>
+    where
+        E: ContextLayers,
+    {
+        self.into_target().map_err(|e| if e.has_context() {
+            e
+        } else {
+            e.push_context(__into_context(f()))
+        })
+    }
+    /// Adds the given context only if the error doesn't have any context yet.
+    /// This is the eager counterpart of `or_else_context()`.
+    fn or_context(self, msg: impl Into<String>) -> std::result::Result<T, E>
+    where
+        E: ContextLayers,
+    {
+        let msg = msg.into();
+        self.or_else_context(|| msg)
+    }
+    /// Adds key-value fields to the outermost context of the error.
+    /// If there is no context yet, a context with an empty message is created.
+    #[cfg(feature = "fields")]
+    fn with_fields<I, K, V>(self, f: impl FnOnce()->I) -> std::result::Result<T, E>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+        E: ContextLayers,
+    {
+        self.or_else_context(String::new).map_err(|mut e| {
+            e.extend_context_fields(f().into_iter().map(|(k, v)| (k.into(), v.into())).collect());
+            e
+        })
+    }
+    /// Adds the context returned by the closure with the given severity.
+    /// The contexts added by other methods have the default severity [Severity::Error].
+    #[cfg(feature = "severity")]
+    fn with_context_severity<S: std::fmt::Display>(self, severity: Severity, f: impl FnOnce()->S) -> std::result::Result<T, E>
+    where
+        E: ContextLayers,
+    {
+        self.with_context(f).map_err(|mut e| {
+            e.set_context_severity(severity);
+            e
+        })
+    }
+}
+
+/// Extension trait for adding context to `Result`s with boxed dynamic errors.
+/// Implemented by `#[string_context("...", boxed_error = MyError::Other)]`,
+/// which stores the boxed errors in the designated variant.
+///
+/// If the designated variant contains a `String`, it is implemented for `Box<dyn Error>`
+/// and `Box<dyn Error + Send + Sync>` and the message of the error is stored.
+/// Otherwise it is implemented for the type of the variant field and the boxed error
+/// itself is stored, so it could be used as a `#[source]`.
+pub trait AddBoxedErrorContext<E,T> {
+    /// Stores the boxed error in the designated variant and adds the context to it.
+    fn with_boxed_context<S: std::fmt::Display>(self, f: impl FnOnce()->S) -> std::result::Result<T, E>;
+}
+
+/// Adds the context to the error converting it into the explicitly given enum.
+///
+/// If the error converts into several annotated enums, the target of `with_context()`
+/// could be ambiguous. This function names the target explicitly:
+/// ```
+/// use thiserror::Error;
+/// use thiserror_string_context::*;
+///
+/// #[string_context("Config: {0}")]
+/// #[derive(Error,Debug)]
+/// enum ConfigError {
+///     #[error(transparent)]
+///     Io(#[from] std::io::Error),
+/// }
+///
+/// #[string_context("Network: {0}")]
+/// #[derive(Error,Debug)]
+/// enum NetworkError {
+///     #[error(transparent)]
+///     Io(#[from] std::io::Error),
+/// }
+///
+/// fn read() -> std::io::Result<String> {
+///     Err(std::io::ErrorKind::NotFound.into())
+/// }
+///
+/// let err = with_context::<ConfigError, _, _>(read(), || "reading config").unwrap_err();
+/// assert_eq!(err.to_string(), "Config: reading config");
+/// let err = with_context::<NetworkError, _, _>(read(), || "reading socket").unwrap_err();
+/// assert_eq!(err.to_string(), "Network: reading socket");
+/// ```
+pub fn with_context<E, T, S: std::fmt::Display>(
+    res: impl AddErrorContext<E, T>,
+    f: impl FnOnce()->S,
+) -> std::result::Result<T, E> {
+    res.with_context(f)
+}
+
+/// Runs the fallible closure and adds the context to its error.
+///
+/// This annotates all errors propagated with `?` inside the closure at once:
+/// `run_with_context(|| "loading profile", || -> Result<_, MyError> { ... })`.
+/// The error type of the closure usually has to be given explicitly, since it is
+/// not inferred through `?`. The [context_block!] macro does this for the enum.
+pub fn run_with_context<E, T, Src, S: std::fmt::Display>(
+    ctx: impl FnOnce()->S,
+    body: impl FnOnce()->std::result::Result<T, Src>,
+) -> std::result::Result<T, E>
+where
+    std::result::Result<T, Src>: AddErrorContext<E, T>,
+{
+    body().with_context(ctx)
+}
+
+/// Converts the error with the given function and adds the context to it.
+///
+/// It is implemented for all results, so the error doesn't have to convert into
+/// the annotated enum: `res.map_err_context(MyError::Parse, || "parsing")` is the same as
+/// `res.map_err(MyError::Parse).with_context(|| "parsing")`.
+pub trait AddMappedErrorContext<T>: ErrorType {
+    /// Converts the error with `variant_fn` and adds the context returned by the closure.
+    /// Both closures are only called if there is an error.
+    fn map_err_context<E, S: std::fmt::Display>(
+        self,
+        variant_fn: impl FnOnce(Self::Error)->E,
+        f: impl FnOnce()->S,
+    ) -> std::result::Result<T, E>
+    where
+        std::result::Result<T, E>: AddErrorContext<E, T>;
+}
+
+impl<T, Src> AddMappedErrorContext<T> for std::result::Result<T, Src> {
+    fn map_err_context<E, S: std::fmt::Display>(
+        self,
+        variant_fn: impl FnOnce(Src)->E,
+        f: impl FnOnce()->S,
+    ) -> std::result::Result<T, E>
+    where
+        std::result::Result<T, E>: AddErrorContext<E, T>,
+    {
+        self.map_err(variant_fn).with_context(f)
+    }
+}
+
+/// Adds the context to the errors of futures resolving to results.
+///
+/// `fetch(url).with_context(|| format!("fetching {url}")).await?` is the same as
+/// `fetch(url).await.with_context(|| format!("fetching {url}"))?`.
+/// The closure is only called if the future resolves to an error.
+#[cfg(feature = "async")]
+pub trait AddFutureContext<T, Src>: std::future::Future<Output = std::result::Result<T, Src>> + Sized {
+    /// Wraps the future into [ContextFuture], which adds the context returned by the closure to the error.
+    fn with_context<E, S, F>(self, f: F) -> ContextFuture<Self, F, E>
+    where
+        S: std::fmt::Display,
+        F: FnOnce()->S,
+        std::result::Result<T, Src>: AddErrorContext<E, T>;
+}
+
+#[cfg(feature = "async")]
+impl<Fut, T, Src> AddFutureContext<T, Src> for Fut
+where
+    Fut: std::future::Future<Output = std::result::Result<T, Src>>,
+{
+    fn with_context<E, S, F>(self, f: F) -> ContextFuture<Self, F, E>
+    where
+        S: std::fmt::Display,
+        F: FnOnce()->S,
+        std::result::Result<T, Src>: AddErrorContext<E, T>,
+    {
+        ContextFuture { fut: self, f: Some(f), target: std::marker::PhantomData }
+    }
+}
+
+#[cfg(feature = "async")]
+pin_project_lite::pin_project! {
+    /// Future returned by [AddFutureContext::with_context].
+    ///
+    /// It is `Send` if the wrapped future and the closure are `Send`
+    /// and `Unpin` if the wrapped future and the closure are `Unpin`.
+    pub struct ContextFuture<Fut, F, E> {
+        #[pin]
+        fut: Fut,
+        f: Option<F>,
+        target: std::marker::PhantomData<fn() -> E>,
+    }
+}
+
+#[cfg(feature = "async")]
+impl<Fut, F, E, T, Src, S> std::future::Future for ContextFuture<Fut, F, E>
+where
+    Fut: std::future::Future<Output = std::result::Result<T, Src>>,
+    F: FnOnce()->S,
+    S: std::fmt::Display,
+    std::result::Result<T, Src>: AddErrorContext<E, T>,
+{
+    type Output = std::result::Result<T, E>;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
+        let this = self.project();
+        this.fut.poll(cx).map(|res| {
+            let f = this.f.take().expect("ContextFuture polled after completion");
+            res.with_context(f)
+        })
+    }
+}
+
+/// Extension trait for adding context to the error items of streams of `Result`s.
+/// Implemented for all such streams with the `streams` feature.
+#[cfg(feature = "streams")]
+pub trait AddStreamContext<T, Src>: futures_core::Stream<Item = std::result::Result<T, Src>> + Sized {
+    /// Wraps the stream into [ContextStream], which adds the context returned by the closure
+    /// to every error item. The closure is called once for every error, the `Ok` items are passed as is.
+    fn with_item_context<E, S, F>(self, f: F) -> ContextStream<Self, F, E>
+    where
+        S: std::fmt::Display,
+        F: FnMut()->S,
+        std::result::Result<T, Src>: AddErrorContext<E, T>;
+}
+
+#[cfg(feature = "streams")]
+impl<St, T, Src> AddStreamContext<T, Src> for St
+where
+    St: futures_core::Stream<Item = std::result::Result<T, Src>>,
+{
+    fn with_item_context<E, S, F>(self, f: F) -> ContextStream<Self, F, E>
+    where
+        S: std::fmt::Display,
+        F: FnMut()->S,
+        std::result::Result<T, Src>: AddErrorContext<E, T>,
+    {
+        ContextStream { stream: self, f, target: std::marker::PhantomData }
+    }
+}
+
+#[cfg(feature = "streams")]
+pin_project_lite::pin_project! {
+    /// Stream returned by [AddStreamContext::with_item_context].
+    ///
+    /// It is `Send` if the wrapped stream and the closure are `Send`
+    /// and `Unpin` if the wrapped stream and the closure are `Unpin`.
+    pub struct ContextStream<St, F, E> {
+        #[pin]
+        stream: St,
+        f: F,
+        target: std::marker::PhantomData<fn() -> E>,
+    }
+}
+
+#[cfg(feature = "streams")]
+impl<St, F, E, T, Src, S> futures_core::Stream for ContextStream<St, F, E>
+where
+    St: futures_core::Stream<Item = std::result::Result<T, Src>>,
+    F: FnMut()->S,
+    S: std::fmt::Display,
+    std::result::Result<T, Src>: AddErrorContext<E, T>,
+{
+    type Item = std::result::Result<T, E>;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
+        let this = self.project();
+        let f = this.f;
+        this.stream.poll_next(cx).map(|item| item.map(|res| res.with_context(f)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+/// Collects the results of processing the items of an iterator,
+/// adding the context about the failing item to the error.
+///
+/// ```
+/// use thiserror::Error;
+/// use thiserror_string_context::*;
+///
+/// #[string_context("Custom context message: {0}")]
+/// #[derive(Error,Debug)]
+/// enum MyError {
+///     #[error(transparent)]
+///     Parse(#[from] std::num::ParseIntError),
+/// }
+///
+/// let entries = ["1", "2", "x", "4"];
+/// let res: Result<Vec<i32>, MyError> = entries
+///     .iter()
+///     .try_collect_with_context(|s| s.parse::<i32>(), |i, s| format!("entry {i} ({s})"));
+/// assert_eq!(res.unwrap_err().context_str(), "entry 2 (x)");
+/// ```
+pub trait AddIteratorContext: Iterator + Sized {
+    /// Applies `f` to every item and collects the values into a vector until the first error.
+    /// The error is wrapped into the context returned by `ctx` from the index and the failing item.
+    /// `ctx` is only called for the failing item and the rest of the items are not processed.
+    fn try_collect_with_context<V, Src, E, S>(
+        self,
+        f: impl FnMut(&Self::Item) -> std::result::Result<V, Src>,
+        ctx: impl FnOnce(usize, &Self::Item) -> S,
+    ) -> std::result::Result<Vec<V>, E>
+    where
+        S: std::fmt::Display,
+        std::result::Result<V, Src>: AddErrorContext<E, V>;
+
+    /// Applies `f` to every item until the first error, which is wrapped
+    /// into the context `item N` with the index of the failing item.
+    /// The rest of the items are not processed.
+    fn try_each_with_context<Src, E>(
+        self,
+        f: impl FnMut(Self::Item) -> std::result::Result<(), Src>,
+    ) -> std::result::Result<(), E>
+    where
+        std::result::Result<(), Src>: AddErrorContext<E, ()>;
+}
+
+impl<I: Iterator> AddIteratorContext for I {
+    fn try_collect_with_context<V, Src, E, S>(
+        self,
+        mut f: impl FnMut(&Self::Item) -> std::result::Result<V, Src>,
+        ctx: impl FnOnce(usize, &Self::Item) -> S,
+    ) -> std::result::Result<Vec<V>, E>
+    where
+        S: std::fmt::Display,
+        std::result::Result<V, Src>: AddErrorContext<E, V>,
+    {
+        let mut values = Vec::new();
+        for (i, item) in self.enumerate() {
+            match f(&item) {
+                Ok(v) => values.push(v),
+                err => return err.with_context(|| ctx(i, &item)).map(|_| values),
+            }
+        }
+        Ok(values)
+    }
+
+    fn try_each_with_context<Src, E>(
+        self,
+        mut f: impl FnMut(Self::Item) -> std::result::Result<(), Src>,
+    ) -> std::result::Result<(), E>
+    where
+        std::result::Result<(), Src>: AddErrorContext<E, ()>,
+    {
+        for (i, item) in self.enumerate() {
+            f(item).with_context_args(|w| w(format_args!("item {i}")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds the context to foreign errors, which don't convert into the annotated enum.
+///
+/// It is implemented for the results with any `Send + Sync` error by the [string_context] macro
+/// with the `external` option. The error is boxed and stored in the hidden variant.
+pub trait AddExternalContext<E, T> {
+    /// Stores the error together with the context returned by the closure
+    fn with_external_context<S: std::fmt::Display>(self, f: impl FnOnce() -> S) -> std::result::Result<T, E>;
+}
+
+/// Access to the context layers of annotated enums.
+///
+/// It is implemented for all annotated enums by the [string_context] macro
+/// and allows to move the contexts between different enums.
+pub trait ContextLayers: Sized {
+    /// Wraps the error into the new outermost context
+    fn push_context(self, ctx: String) -> Self;
+    /// Removes the outermost context if there is any
+    fn pop_context(self) -> (Option<String>, Self);
+    /// Returns all contexts starting from the outermost one including the contexts
+    /// of other annotated enums stored in the `#[from]` or `#[source]` fields of the base error
+    fn flatten_contexts(&self) -> Vec<&str>;
+    /// Returns true if the error has at least one context
+    fn has_context(&self) -> bool;
+    /// Adds the key-value fields to the outermost context, nothing is done without a context
+    #[cfg(feature = "fields")]
+    fn extend_context_fields(&mut self, fields: Vec<(String, String)>);
+    /// Sets the severity of the outermost context, nothing is done without a context
+    #[cfg(feature = "severity")]
+    fn set_context_severity(&mut self, severity: Severity);
+}
+
+// Collects the contexts of the source fields, which are annotated enums,
+// while the other sources are skipped. The impl for the annotated enums
+// is picked by the method resolution before the autoref'ed fallback.
+#[doc(hidden)]
+pub struct NestedContexts<'a, T>(pub &'a T);
+
+#[doc(hidden)]
+pub trait NestedAnnotatedContexts<'a> {
+    fn nested_contexts(&self) -> Vec<&'a str>;
+}
+
+impl<'a, T: ContextLayers> NestedAnnotatedContexts<'a> for NestedContexts<'a, T> {
+    fn nested_contexts(&self) -> Vec<&'a str> {
+        self.0.flatten_contexts()
+    }
+}
+
+#[doc(hidden)]
+pub trait NestedOtherContexts<'a> {
+    fn nested_contexts(&self) -> Vec<&'a str>;
+}
+
+impl<'a, T> NestedOtherContexts<'a> for &NestedContexts<'a, T> {
+    fn nested_contexts(&self) -> Vec<&'a str> {
+        Vec::new()
+    }
+}
+
+/// Converts the annotated error into another annotated enum keeping its contexts.
+///
+/// The base error is converted with `From` and all the contexts are re-attached
+/// to it as the contexts of the target enum, so they are not hidden inside the converted variant.
+pub fn convert_context<Src: ContextLayers, E: ContextLayers + From<Src>>(err: Src) -> E {
+    let mut contexts = Vec::new();
+    let mut err = err;
+    let base = loop {
+        match err.pop_context() {
+            (Some(ctx), inner) => {
+                contexts.push(ctx);
+                err = inner;
+            }
+            (None, base) => break base,
+        }
+    };
+    contexts.into_iter().rev().fold(E::from(base), |inner, ctx| inner.push_context(ctx))
+}
+
+/// Adds the context to the error converting it into another annotated enum.
+///
+/// Unlike `with_context()`, the contexts of the original error are kept as the contexts
+/// of the target enum, see [convert_context].
+pub trait AddConvertedContext<T, Src> {
+    /// Converts the error with [convert_context] and adds the context returned by the closure.
+    fn with_context_into<E, S: std::fmt::Display>(self, f: impl FnOnce()->S) -> std::result::Result<T, E>
+    where
+        E: ContextLayers + From<Src>;
+}
+
+impl<T, Src: ContextLayers> AddConvertedContext<T, Src> for std::result::Result<T, Src> {
+    fn with_context_into<E, S: std::fmt::Display>(self, f: impl FnOnce()->S) -> std::result::Result<T, E>
+    where
+        E: ContextLayers + From<Src>,
+    {
+        self.map_err(|e| convert_context::<Src, E>(e).push_context(__into_context(f())))
+    }
+}
+
+/// Runs a side effect on the context of the error without consuming it.
+///
+/// It is implemented for results of the annotated enums by the [string_context] macro.
+pub trait InspectErrorContext<E>: Sized {
+    /// Calls the closure with the outermost context if the error has any context.
+    /// The result is returned unchanged.
+    fn inspect_context(self, f: impl FnOnce(&str)) -> Self;
+}
+
+type Localizer = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+static CONTEXT_LOCALIZER: std::sync::RwLock<Option<Localizer>> = std::sync::RwLock::new(None);
+
+/// Registers the function returning the localized message of the context variant by its key.
+///
+/// It is used by the enums annotated with `#[string_context(key = "err.context", localized)]`.
+/// The `{0}` placeholders in the returned message are replaced by the context string.
+/// Without the registered localizer the key itself is used as the message.
+pub fn set_context_localizer(f: impl Fn(&str) -> String + Send + Sync + 'static) {
+    *CONTEXT_LOCALIZER.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(f));
+}
+
+/// Renders the localized message of the context variant.
+#[doc(hidden)]
+pub struct LocalizedContext<'a>(pub &'static str, pub &'a str);
+
+impl std::fmt::Display for LocalizedContext<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let localizer = CONTEXT_LOCALIZER.read().unwrap_or_else(|e| e.into_inner());
+        match localizer.as_ref() {
+            Some(localize) => f.write_str(&localize(self.0).replace("{0}", self.1)),
+            None => f.write_str(self.0),
+        }
+    }
+}
+
+/// Severity of the context stored with the `severity` feature.
+#[cfg(feature = "severity")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Severity {
+    Info,
+    Warning,
+    #[default]
+    Error,
+    Fatal,
+}
+
+#[cfg(feature = "severity")]
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+            Severity::Fatal => "fatal",
+        })
+    }
+}
+
+/// Displays the severity prefix like `[warning] ` of the context variant.
+#[cfg(feature = "severity")]
+#[doc(hidden)]
+pub struct SeverityPrefix<'a>(pub &'a Severity);
+
+#[cfg(feature = "severity")]
+impl std::fmt::Display for SeverityPrefix<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] ", self.0)
+    }
+}
 
-pub use thiserror_string_context_macro::string_context;
+/// Owned report of an error returned by `to_report()` of the annotated enums
+/// with the `report` option and the `serde` feature, for example to be sent as a JSON body of the response.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ErrorReport {
+    /// The message of the base error
+    pub message: String,
+    /// The contexts starting from the outermost one
+    pub contexts: Vec<String>,
+    /// The name of the base variant
+    pub kind: String,
+}
+
+#[cfg(feature = "serde")]
+impl ErrorReport {
+    /// Renders the report as a JSON object with the `message`, `contexts` and `kind` fields.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("the report only contains strings")
+    }
+}
+
+/// Displays the optional typed field of the context or nothing if it is not set.
+#[doc(hidden)]
+pub struct DisplayOption<'a, T>(pub &'a Option<T>);
+
+impl<T: std::fmt::Display> std::fmt::Display for DisplayOption<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(value) => value.fmt(f),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Displays the key-value fields of the context as ` key=value` pairs.
+#[cfg(feature = "fields")]
+#[doc(hidden)]
+pub struct DisplayFields<'a>(pub &'a [(String, String)]);
+
+#[cfg(feature = "fields")]
+impl std::fmt::Display for DisplayFields<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (key, value) in self.0 {
+            write!(f, " {key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The thread, where the context was added. It is stored in the context variant
+/// with the `thread-name` feature.
+///
+/// Storing the thread handle doesn't allocate. The threads are compared by their ids.
+#[cfg(feature = "thread-name")]
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct ContextThread(std::thread::Thread);
+
+#[cfg(feature = "thread-name")]
+impl ContextThread {
+    pub fn current() -> Self {
+        ContextThread(std::thread::current())
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.0.name()
+    }
+}
+
+#[cfg(feature = "thread-name")]
+impl PartialEq for ContextThread {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.id() == other.0.id()
+    }
+}
+
+#[cfg(feature = "thread-name")]
+impl Eq for ContextThread {}
+
+#[cfg(feature = "thread-name")]
+impl std::hash::Hash for ContextThread {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.id().hash(state)
+    }
+}
+
+thread_local! {
+    static CONTEXT_SCOPES: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Guard of the scoped context created by [context_scope].
+///
+/// The context is removed from the scope when the guard is dropped,
+/// including during unwinding. The guard can't be sent to other threads.
+pub struct ContextScope {
+    depth: usize,
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl Drop for ContextScope {
+    fn drop(&mut self) {
+        CONTEXT_SCOPES.with(|scopes| scopes.borrow_mut().truncate(self.depth));
+    }
+}
+
+/// Declares the context for the current scope of the current thread.
+///
+/// While the returned guard is alive, [scoped_context!] adds this context
+/// to the errors. Nested scopes become nested contexts with the innermost scope
+/// giving the innermost context. The scopes of other threads are not visible.
+pub fn context_scope(label: impl Into<String>) -> ContextScope {
+    CONTEXT_SCOPES.with(|scopes| {
+        let mut scopes = scopes.borrow_mut();
+        scopes.push(label.into());
+        ContextScope { depth: scopes.len() - 1, _not_send: std::marker::PhantomData }
+    })
+}
+
+/// Calls the closure with the contexts of the active scopes of the current thread
+/// starting from the outermost one.
+pub fn with_context_scopes<R>(f: impl FnOnce(&[String]) -> R) -> R {
+    CONTEXT_SCOPES.with(|scopes| f(&scopes.borrow()))
+}
+
+/// Gives the error type of a result before it is converted into the annotated enum.
+///
+/// It is used by `with_context_with()` to pass the original error to the closure.
+/// The type doesn't depend on the target enum, so it is known before the target is inferred.
+pub trait ErrorType {
+    /// The error being wrapped
+    type Error;
+}
+
+impl<T, E> ErrorType for std::result::Result<T, E> {
+    type Error = E;
+}
+
+/// Converts `None` into the given error with the context added to it.
+///
+/// It is implemented for `Option<T>` and works with all annotated enums,
+/// which implement [AddErrorContext].
+pub trait AddOptionContext<T> {
+    /// Converts `None` into the error wrapped into the context returned by the closure.
+    /// The closure is only called if the value is `None`.
+    fn ok_or_ctx<E, S: std::fmt::Display>(self, err: E, f: impl FnOnce()->S) -> std::result::Result<T, E>
+    where
+        std::result::Result<T, E>: AddErrorContext<E, T>;
+}
+
+impl<T> AddOptionContext<T> for Option<T> {
+    fn ok_or_ctx<E, S: std::fmt::Display>(self, err: E, f: impl FnOnce()->S) -> std::result::Result<T, E>
+    where
+        std::result::Result<T, E>: AddErrorContext<E, T>,
+    {
+        match self {
+            Some(v) => Ok(v),
+            None => std::result::Result::<T, E>::Err(err).with_context(f),
+        }
+    }
+}
+
+/// Displays the error followed by the chain of its sources in the same way as `anyhow` does:
+/// ```text
+/// Outermost message
+///
+/// Caused by:
+///     0: First cause
+///     1: Second cause
+/// ```
+/// If there is only one cause it is not numbered.
+pub struct Chain<'a, E: ?Sized>(pub &'a E);
+
+impl<E: std::error::Error + ?Sized> std::fmt::Display for Chain<'_, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut causes = std::iter::successors(self.0.source(), |err| err.source()).peekable();
+        let Some(first) = causes.next() else {
+            return Ok(());
+        };
+        write!(f, "\n\nCaused by:")?;
+        if causes.peek().is_none() {
+            write!(f, "\n    {}", first)
+        } else {
+            for (i, cause) in std::iter::once(first).chain(causes).enumerate() {
+                write!(f, "\n    {}: {}", i, cause)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Wrapper of the error returned from `main`, which `Debug` shows the error
+/// with the chain of its causes as [Chain] does.
+///
+/// `main` returning `Result<(), MyError>` prints the error with its `Debug`, which shows
+/// the nested hidden variants, so the error should be converted into the wrapper by `?` instead.
+/// The process exits with the code 1 then:
+/// ```no_run
+/// use thiserror::Error;
+/// use thiserror_string_context::*;
+///
+/// #[string_context("Custom context message: {0}")]
+/// #[derive(Error,Debug)]
+/// enum MyError {
+///     #[error("Slight underflow happened!")]
+///     Underflow,
+/// }
+///
+/// fn main() -> Result<(), MainError<MyError>> {
+///     Err(MyError::Underflow).with_context(|| "Crashing with value 41")?;
+///     Ok(())
+/// }
+/// ```
+#[cfg(feature = "termination")]
+pub struct MainError<E>(pub E);
+
+#[cfg(feature = "termination")]
+impl<E: std::error::Error> std::fmt::Debug for MainError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&Chain(&self.0), f)
+    }
+}
+
+#[cfg(feature = "termination")]
+impl<E: std::error::Error> From<E> for MainError<E> {
+    fn from(err: E) -> Self {
+        Self(err)
+    }
+}
+
+/// Adds the context to the error and propagates it with `?`.
+///
+/// `try_context!(expr, "loading {}", name)` is equivalent to
+/// `expr.with_context(|| format!("loading {}", name))?`, but formats the context
+/// without an intermediate `String`.
+/// The format arguments are only evaluated if there is an error.
+#[macro_export]
+macro_rules! try_context {
+    ($expr:expr, $($arg:tt)+) => {
+        $crate::AddErrorContext::with_context_args($expr, |w| w(format_args!($($arg)+)))?
+    };
+}
+
+/// Adds the contexts of the active scopes to the error and propagates it with `?`.
+///
+/// This is the entry point for the scoped contexts. The contexts declared by the [context_scope]
+/// guards alive at this point are added to the error with the outermost scope giving
+/// the outermost context. If there are no active scopes, the error is only converted.
+/// ```
+/// use thiserror::Error;
+/// use thiserror_string_context::*;
+///
+/// #[string_context("Custom context message: {0}")]
+/// #[derive(Error,Debug)]
+/// enum MyError {
+///     #[error("Not found")]
+///     NotFound,
+/// }
+///
+/// fn find(id: u32) -> Result<(), MyError> {
+///     let _scope = context_scope(format!("looking up {id}"));
+///     scoped_context!(Err(MyError::NotFound));
+///     Ok(())
+/// }
+///
+/// assert_eq!(find(5).unwrap_err().full_message(), "looking up 5: Not found");
+/// ```
+#[macro_export]
+macro_rules! scoped_context {
+    ($expr:expr) => {
+        $crate::AddErrorContext::with_scoped_context($expr)?
+    };
+}
+
+/// Creates a lazy formatted context for `with_context()`.
+///
+/// `ctx!("reading {}", path.display())` expands to `|| format!("reading {}", path.display())`,
+/// so the full `format!()` syntax is supported including named and captured arguments.
+/// ```
+/// use thiserror::Error;
+/// use thiserror_string_context::*;
+///
+/// #[string_context("Custom context message: {0}")]
+/// #[derive(Error,Debug)]
+/// enum MyError {
+///     #[error("Not found")]
+///     NotFound,
+/// }
+///
+/// let path = "/etc/app.conf";
+/// let res: Result<(), MyError> = Err(MyError::NotFound);
+/// let err = res.with_context(ctx!("reading {path}")).unwrap_err();
+/// assert_eq!(err.context_str(), "reading /etc/app.conf");
+/// ```
+///
+/// If the result is passed first, `ctx!(res, "reading {}", path.display())` adds the context
+/// with `with_context_args()`, so the context is formatted without an intermediate `String`
+/// and only if there is an error:
+/// ```
+/// # use thiserror::Error;
+/// # use thiserror_string_context::*;
+/// #
+/// # #[string_context("Custom context message: {0}")]
+/// # #[derive(Error,Debug)]
+/// # enum MyError {
+/// #     #[error("Not found")]
+/// #     NotFound,
+/// # }
+/// #
+/// let res: Result<(), MyError> = Err(MyError::NotFound);
+/// let err = ctx!(res, "reading {}", "/etc/app.conf").unwrap_err();
+/// assert_eq!(err.context_str(), "reading /etc/app.conf");
+/// ```
+#[macro_export]
+macro_rules! ctx {
+    ($fmt:literal $($arg:tt)*) => {
+        || format!($fmt $($arg)*)
+    };
+    ($res:expr, $($arg:tt)+) => {
+        $crate::AddErrorContext::with_context_args($res, |w| w(format_args!($($arg)+)))
+    };
+}
+
+/// Returns the path of the enclosing function like `my_crate::config::load`.
+///
+/// The closures and async blocks inside the function are skipped, so the path
+/// is the same anywhere in the function body.
+/// ```
+/// use thiserror_string_context::function_name;
+///
+/// fn load() -> Option<&'static str> {
+///     Some(1).map(|_| function_name!())
+/// }
+/// assert!(load().unwrap().ends_with("::load"));
+/// ```
+#[macro_export]
+macro_rules! function_name {
+    () => {{
+        fn __f() {}
+        $crate::__function_name(&__f)
+    }};
+}
+
+#[doc(hidden)]
+pub fn __function_name<T>(_: &T) -> &'static str {
+    let mut name = std::any::type_name::<T>();
+    name = name.strip_suffix("::__f").unwrap_or(name);
+    while let Some(outer) = name.strip_suffix("::{{closure}}") {
+        name = outer;
+    }
+    name
+}
+
+/// Creates the closure formatting the context followed by the path of the enclosing function.
+///
+/// `fn_ctx!("loading {path}")` is the same as `ctx!()`, but appends ` (in my_crate::config::load)`
+/// to the context, so the function name doesn't have to be typed by hand.
+/// ```
+/// use thiserror::Error;
+/// use thiserror_string_context::*;
+///
+/// #[string_context("Custom context message: {0}")]
+/// #[derive(Error,Debug)]
+/// enum MyError {
+///     #[error("Not found")]
+///     NotFound,
+/// }
+///
+/// fn load_config(path: &str) -> Result<(), MyError> {
+///     Err(MyError::NotFound).with_context(fn_ctx!("loading {path}"))
+/// }
+///
+/// let err = load_config("app.conf").unwrap_err();
+/// assert!(err.context_str().starts_with("loading app.conf (in "));
+/// assert!(err.context_str().ends_with("::load_config)"));
+/// ```
+#[macro_export]
+macro_rules! fn_ctx {
+    ($fmt:literal $($arg:tt)*) => {
+        || format!("{} (in {})", format_args!($fmt $($arg)*), $crate::function_name!())
+    };
+}
+
+/// Runs the block returning a result of the given annotated enum and adds the context to its error.
+///
+/// `context_block!(MyError, { ... }, "loading {}", name)` runs the block as a closure, so `?`
+/// inside the block converts the errors into `MyError`, and adds the formatted context
+/// to the error if the block fails. The resulting `Result<T, MyError>` could be propagated with `?`.
+/// ```
+/// use thiserror::Error;
+/// use thiserror_string_context::*;
+///
+/// #[string_context("Custom context message: {0}")]
+/// #[derive(Error,Debug)]
+/// enum MyError {
+///     #[error(transparent)]
+///     Parse(#[from] std::num::ParseIntError),
+/// }
+///
+/// fn load(id: u32, a: &str, b: &str) -> Result<i32, MyError> {
+///     let sum = context_block!(MyError, {
+///         let a: i32 = a.parse()?;
+///         let b: i32 = b.parse()?;
+///         Ok(a + b)
+///     }, "loading profile {id}")?;
+///     Ok(sum)
+/// }
+///
+/// assert_eq!(load(1, "1", "2").unwrap(), 3);
+/// assert_eq!(load(2, "1", "x").unwrap_err().context_str(), "loading profile 2");
+/// ```
+#[macro_export]
+macro_rules! context_block {
+    ($ty:ty, $body:block, $($arg:tt)+) => {
+        $crate::with_context::<$ty, _, _>(
+            (|| -> ::std::result::Result<_, $ty> { $body })(),
+            || format!($($arg)+),
+        )
+    };
+}
+
+/// Asserts that the result is an error, which base variant matches the pattern ignoring the context.
+///
+/// `assert_error_matches!(result, MyError::Error1)` panics with the message showing
+/// the actual context and the base error if the result is `Ok` or the error doesn't match.
+/// Guards are supported: `assert_error_matches!(result, MyError::Overflow(n) if n > 1)`.
+#[cfg(feature = "testing")]
+#[macro_export]
+macro_rules! assert_error_matches {
+    ($result:expr, $pattern:pat $(if $guard:expr)? $(,)?) => {
+        match &$result {
+            Ok(_) => panic!(
+                "assertion failed: expected error matching `{}`, got Ok",
+                stringify!($pattern $(if $guard)?),
+            ),
+            Err(err) => {
+                if !matches!(err.base_error(), $pattern $(if $guard)?) {
+                    panic!(
+                        "assertion failed: error `{:?}` with context {:?} doesn't match `{}`",
+                        err.base_error(),
+                        err.outermost_context(),
+                        stringify!($pattern $(if $guard)?),
+                    );
+                }
+            }
+        }
+    };
+}
+
+// Edge cases accepted by the macros, the rejected ones are checked in `tests/ui`
+#[cfg(doctest)]
+mod accepted_tests {
+    /// Escaped braces are fine
+    /// ```
+    /// use thiserror::Error;
+    /// use thiserror_string_context::*;
+    ///
+    /// #[string_context("{{escaped}}: {0}")]
+    /// #[derive(Error,Debug)]
+    /// enum MyError {
+    ///     #[error("Error 1")]
+    ///     Error1,
+    /// }
+    /// ```
+    struct InvalidPlaceholders;
 
-pub trait AddErrorContext<E,T,S> {
-    fn with_context(self, f: impl FnOnce()->S) -> std::result::Result<T, E>;
+    /// Debug and alignment are fine for the context string, alignment for the fields
+    /// ```
+    /// use thiserror::Error;
+    /// use thiserror_string_context::*;
+    ///
+    /// #[string_context("{code:<4}: {0:?} {0:>10}")]
+    /// #[derive(Error,Debug)]
+    /// enum MyError {
+    ///     #[error("Error 1")]
+    ///     Error1 { code: u32 },
+    /// }
+    /// ```
+    struct InvalidFormats;
+
+    /// Generated methods could be called wherever the enum is visible
+    /// ```
+    /// mod outer {
+    ///     pub mod inner {
+    ///         use thiserror::Error;
+    ///         use thiserror_string_context::*;
+    ///
+    ///         #[string_context]
+    ///         #[derive(Error,Debug)]
+    ///         pub(in crate::outer) enum MyError {
+    ///             #[error("Error 1")]
+    ///             Error1,
+    ///         }
+    ///     }
+    ///
+    ///     pub fn has_context() -> bool {
+    ///         inner::MyError::Error1.unwrap_context().0.is_some()
+    ///     }
+    /// }
+    ///
+    /// fn main() {
+    ///     assert!(!outer::has_context());
+    /// }
+    /// ```
+    struct Visibility;
+
+    /// The derive could be given by its path or inside `cfg_attr`
+    /// ```
+    /// use thiserror_string_context::*;
+    ///
+    /// #[string_context]
+    /// #[derive(thiserror::Error,Debug)]
+    /// enum PathError {
+    ///     #[error("Error 1")]
+    ///     Error1,
+    /// }
+    ///
+    /// #[string_context]
+    /// #[cfg_attr(all(), derive(thiserror::Error))]
+    /// #[derive(Debug)]
+    /// enum CfgError {
+    ///     #[error("Error 1")]
+    ///     Error1,
+    /// }
+    /// ```
+    struct MissingDerive;
+
+    /// Propagating the errors is fine
+    /// ```
+    /// #![deny(unused_must_use)]
+    /// use thiserror::Error;
+    /// use thiserror_string_context::*;
+    ///
+    /// #[string_context]
+    /// #[derive(Error,Debug)]
+    /// enum MyError {
+    ///     #[error("Error 1")]
+    ///     Error1,
+    /// }
+    ///
+    /// fn check() -> Result<(), MyError> {
+    ///     Err(MyError::Error1.or_context("checking"))?;
+    ///     Err(MyError::with_ctx("wrapping", MyError::Error1).strip_context())
+    /// }
+    ///
+    /// fn main() {
+    ///     let _ = check();
+    /// }
+    /// ```
+    struct MustUse;
+
+    /// Without the localizer the key is displayed
+    /// ```
+    /// use thiserror::Error;
+    /// use thiserror_string_context::*;
+    ///
+    /// #[string_context(key = "err.context", localized)]
+    /// #[derive(Error,Debug)]
+    /// enum MyError {
+    ///     #[error("Error 1")]
+    ///     Error1,
+    /// }
+    ///
+    /// let err = MyError::with_ctx("ctx", MyError::Error1);
+    /// assert_eq!(err.to_string(), "err.context");
+    /// ```
+    struct Localized;
 }
 
 #[cfg(test)]
 mod tests {
     use thiserror::Error;
     use super::*;
+    use std::cell::Cell;
 
-    #[string_context("Custom context messag: {0}")]
+    #[string_context("Custom context messag: {0}", try_each, scopes)]
     #[derive(Error,Debug)]
     enum MyError {
         #[error("Error 1")]
@@ -114,5 +1370,2207 @@ mod tests {
         callme(1).with_context(|| "Crashing with value 1").unwrap();
     }
 
+    #[string_context("Kind context: {0}", kind)]
+    #[derive(Error,Debug)]
+    enum KindError {
+        #[error("Unit")]
+        Unit,
+        #[error("Tuple {0}")]
+        Tuple(i32),
+        #[error("Struct {code}")]
+        Struct { code: u32 },
+    }
+
+    #[test]
+    fn test_kind() {
+        let err: Result<(),KindError> = Err(KindError::Tuple(1));
+        let err = err.with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        assert_eq!(err.kind(), KindErrorKind::Tuple);
+        assert_eq!(KindError::Struct { code: 1 }.kind(), KindErrorKind::Struct);
+        // Exhaustive match without a wildcard
+        let name = match err.kind() {
+            KindErrorKind::Unit => "unit",
+            KindErrorKind::Tuple => "tuple",
+            KindErrorKind::Struct => "struct",
+        };
+        assert_eq!(name, "tuple");
+        assert!(matches!(KindError::Unit.kind(), KindErrorKind::Unit));
+        assert_eq!(err.kind_name(), "Tuple");
+        assert_eq!(KindError::Unit.kind_name(), "Unit");
+    }
+
+    #[string_context("at {file}:{line}: {0}", fields(file: String, line: u32))]
+    #[derive(Error,Debug)]
+    enum SyntaxError {
+        #[error("Unexpected token")]
+        Token,
+        #[error("Unclosed bracket at {line}")]
+        Bracket { line: u32 },
+    }
+
+    fn parse_line(file: &str, line: u32) -> Result<(), SyntaxError> {
+        Err(SyntaxError::Token).with_context_fields(|| "parsing", file.to_string(), line)
+    }
+
+    #[test]
+    fn test_typed_context_fields() {
+        let err = parse_line("main.conf", 7).unwrap_err();
+        assert_eq!(err.to_string(), "at main.conf:7: parsing");
+        assert_eq!(err.context_field_line(), Some(&7));
+        assert_eq!(err.context_field_file().map(String::as_str), Some("main.conf"));
+
+        // Plain contexts don't have the fields, so the accessors look deeper
+        let res: Result<(), SyntaxError> = Err(err);
+        let err = res.with_context(|| "loading").unwrap_err();
+        assert_eq!(err.to_string(), "at :: loading");
+        assert_eq!(err.context_field_line(), Some(&7));
+        assert_eq!(err.full_message(), "loading: parsing: Unexpected token");
+
+        // The typed field shadows the field of the base variant
+        let res: Result<(), SyntaxError> = Err(SyntaxError::Bracket { line: 1 });
+        let err = res.with_context_fields(|| "nested", "a.conf".into(), 2).unwrap_err();
+        assert_eq!(err.to_string(), "at a.conf:2: nested");
+        assert_eq!(SyntaxError::Token.context_field_line(), None);
+    }
+
+    #[derive(Debug)]
+    struct VendorError(u32);
+
+    impl std::fmt::Display for VendorError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "vendor failure {}", self.0)
+        }
+    }
+
+    impl std::error::Error for VendorError {}
+
+    #[string_context(external, kind, parts, pretty_debug)]
+    #[derive(Error)]
+    enum ExternalError {
+        #[error("Invalid input")]
+        Invalid,
+    }
+
+    fn call_vendor(code: u32) -> Result<(), ExternalError> {
+        let res: Result<(), VendorError> = Err(VendorError(code));
+        res.with_external_context(|| format!("calling vendor {code}"))
+    }
+
+    #[test]
+    fn test_external_context() {
+        let res: Result<(), ExternalError> = call_vendor(7);
+        let err = res.with_context(|| "syncing").unwrap_err();
+        assert_eq!(err.full_message(), "syncing: calling vendor 7: vendor failure 7");
+        let (ctx, source) = err.external_error().unwrap();
+        assert_eq!(ctx, "calling vendor 7");
+        assert_eq!(source.downcast_ref::<VendorError>().unwrap().0, 7);
+        assert_eq!(err.kind(), ExternalErrorKind::External);
+        assert_eq!(err.kind_name(), "External");
+        assert_eq!(Chain(&err).to_string(), "syncing\n\nCaused by:\n    0: calling vendor 7\n    1: vendor failure 7");
+
+        // The external error is the base error
+        let (outer, base) = err.unwrap_context();
+        assert_eq!(outer.as_deref(), Some("syncing"));
+        assert!(!base.has_context());
+        match base.into_parts().1 {
+            ExternalErrorBase::Invalid => panic!("wrong variant"),
+            ExternalErrorBase::External(ctx, source) => {
+                assert_eq!(ctx, "calling vendor 7");
+                assert_eq!(source.to_string(), "vendor failure 7");
+            }
+        }
+        assert!(ExternalError::Invalid.external_error().is_none());
+        assert_eq!(format!("{:?}", call_vendor(1).unwrap_err()), "External(\"calling vendor 1\", VendorError(1))");
+    }
+
+    #[string_context("Parts context: {0}", parts)]
+    #[derive(Error,Debug)]
+    enum PartsError {
+        #[error("Unit")]
+        Unit,
+        #[error("Tuple {0}")]
+        Tuple(i32, #[source] std::fmt::Error),
+        #[error("Struct {code}")]
+        Struct { code: u32 },
+    }
+
+    fn describe_parts(err: PartsError) -> String {
+        let (ctx, base) = err.into_parts();
+        // Exhaustive match without a wildcard
+        let base = match base {
+            PartsErrorBase::Unit => "unit".to_string(),
+            PartsErrorBase::Tuple(n, e) => format!("tuple {n} {e}"),
+            PartsErrorBase::Struct { code } => format!("struct {code}"),
+        };
+        format!("{} / {base}", ctx.as_deref().unwrap_or("-"))
+    }
+
+    #[test]
+    fn test_into_parts() {
+        assert_eq!(describe_parts(PartsError::Unit), "- / unit");
+        let err: Result<(), PartsError> = Err(PartsError::Tuple(1, std::fmt::Error));
+        let err = err.with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        assert_eq!(describe_parts(err), "Outer: Inner / tuple 1 an error occurred when formatting an argument");
+        let err = PartsError::with_ctx("Only", PartsError::Struct { code: 3 });
+        assert_eq!(describe_parts(err), "Only / struct 3");
+    }
+
+    #[string_context]
+    #[derive(Error,Debug)]
+    enum PredicateError {
+        #[error("Underflow")]
+        Underflow,
+        #[error("Slight overflow {0}")]
+        SlightOverflow(i32),
+        #[error("Http error")]
+        HTTPError,
+        #[error("Custom")]
+        #[skip_is]
+        Custom,
+    }
+
+    impl PredicateError {
+        // Would clash with the generated predicate without #[skip_is]
+        fn is_custom(&self) -> bool {
+            matches!(self.base_error(), PredicateError::Custom)
+        }
+    }
+
+    #[test]
+    fn test_predicates() {
+        let err: Result<(),PredicateError> = Err(PredicateError::SlightOverflow(1));
+        let err = err.with_context(|| "Context").unwrap_err();
+        assert!(err.is_slight_overflow());
+        assert!(!err.is_underflow());
+        assert!(PredicateError::Underflow.is_underflow());
+        assert!(PredicateError::HTTPError.is_http_error());
+        assert!(PredicateError::Custom.is_custom());
+        assert!(!PredicateError::Underflow.is_custom());
+    }
+
+    #[string_context(key = "err.context", localized)]
+    #[derive(Error,Debug)]
+    enum LocalizedError {
+        #[error("Not found")]
+        NotFound,
+    }
+
+    #[test]
+    fn test_localized_context() {
+        let res: Result<(), LocalizedError> = Err(LocalizedError::NotFound);
+        let err = res.with_context(|| "config.toml").unwrap_err();
+        set_context_localizer(|key| match key {
+            "err.context" => "Kontext: {0}".to_string(),
+            key => key.to_string(),
+        });
+        assert_eq!(err.to_string(), "Kontext: config.toml");
+        assert_eq!(err.full_message(), "config.toml: Not found");
+    }
+
+    #[test]
+    #[cfg(feature = "anyhow")]
+    fn test_anyhow_round_trip() {
+        let err = start_app().unwrap_err();
+        let any = err.to_anyhow();
+        assert_eq!(any.to_string(), "starting app");
+        assert_eq!(format!("{any:#}"), "starting app: Config error: parsing app.conf: reading key port: Missing key");
+        assert!(any.downcast_ref::<AppError>().is_some());
+
+        let err = AppError::from_anyhow(any).unwrap();
+        assert_eq!(err.full_message(), "starting app: Config error");
+        assert_eq!(err.flatten_contexts(), ["starting app", "parsing app.conf", "reading key port"]);
+
+        // The anyhow contexts become the contexts of the enum
+        let any = anyhow::Error::new(AppError::Plain.attach_context("inner")).context("middle").context("outer");
+        let err = AppError::from_anyhow(any).unwrap();
+        assert_eq!(err.full_message(), "outer: middle: inner: Plain");
+        let any = err.to_anyhow();
+        assert_eq!(any.chain().map(|e| e.to_string()).collect::<Vec<_>>(), ["outer", "middle", "inner", "Plain"]);
+
+        // Other errors are returned back
+        let any = anyhow::anyhow!("unrelated").context("ctx");
+        let any = AppError::from_anyhow(any).unwrap_err();
+        assert_eq!(format!("{any:#}"), "ctx: unrelated");
+    }
+
+    #[string_context(inspector)]
+    #[derive(Error,Debug)]
+    enum InspectedError {
+        #[error("Lost")]
+        Lost,
+        #[error("Broken")]
+        Broken,
+    }
+
+    #[test]
+    fn test_context_inspector() {
+        // Nothing is called without the inspector
+        let _ = InspectedError::with_ctx("Before", InspectedError::Lost);
+
+        static SEEN: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+        assert!(InspectedError::set_context_inspector(|ctx, err| {
+            SEEN.lock().unwrap().push(format!("{ctx} / {}", err.base_error()));
+        }));
+        assert!(!InspectedError::set_context_inspector(|_, _| panic!("Not installed")));
+
+        let res: Result<(), InspectedError> = Err(InspectedError::Broken);
+        let res: Result<(), InspectedError> = res.with_context(|| "Inner");
+        let err = res.context("Outer").unwrap_err();
+        assert_eq!(err.full_message(), "Outer: Inner: Broken");
+        assert_eq!(*SEEN.lock().unwrap(), ["Inner / Broken", "Outer / Broken"]);
+    }
+
+    #[test]
+    fn test_unit_ok_no_context() {
+        // Side-effecting calls returning `Ok(())` never evaluate the contexts
+        fn save() -> Result<(), IoError> {
+            Ok(())
+        }
+        let res = save()
+            .with_context(|| -> String { unreachable!("The context is not built on success") })
+            .with_context_tap(|| -> String { unreachable!() }, |_| unreachable!())
+            .with_context_if(true, || -> String { unreachable!() });
+        assert!(res.is_ok());
+        let res: Result<(), IoError> = Ok::<(), std::io::Error>(())
+            .map_to_context(|_| unreachable!("The base error is not chosen on success"));
+        assert!(res.is_ok());
+    }
+
+    #[string_context("Display: {0}")]
+    #[derive(Error,Debug)]
+    enum DisplayContextError {
+        #[error("Base {0:?}")]
+        Base(String),
+    }
+
+    #[string_context("Debug: {0:?}")]
+    #[derive(Error,Debug)]
+    enum DebugContextError {
+        #[error("Base {0}")]
+        Base(String),
+    }
+
+    #[test]
+    fn test_context_format() {
+        // The context string is rendered independently of the formatting of the base variant
+        let err = DisplayContextError::with_ctx("say \"hi\"", DisplayContextError::Base("x".into()));
+        assert_eq!(err.to_string(), "Display: say \"hi\"");
+        assert_eq!(err.full_message(), "say \"hi\": Base \"x\"");
+        let err = DebugContextError::with_ctx("say \"hi\"", DebugContextError::Base("x".into()));
+        assert_eq!(err.to_string(), "Debug: \"say \\\"hi\\\"\"");
+        assert_eq!(err.full_message(), "say \"hi\": Base x");
+    }
+
+    #[string_context("Single context: {0}")]
+    #[derive(Error,Debug)]
+    enum SingleError {
+        #[error(transparent)]
+        Utf8(#[from] std::str::Utf8Error),
+    }
+
+    fn decode(bytes: &[u8]) -> Result<&str, SingleError> {
+        let s = std::str::from_utf8(bytes).with_context(|| "decoding name")?;
+        Ok(s)
+    }
+
+    #[test]
+    fn test_single_variant() {
+        assert_eq!(decode(b"name").unwrap(), "name");
+        let err = decode(b"\xFF").unwrap_err();
+        assert_eq!(err.to_string(), "Single context: decoding name");
+        assert!(err.is_utf8());
+        let res: Result<(), SingleError> = Err(err);
+        let err = res.with_context(|| "loading").unwrap_err();
+        assert_eq!(err.flatten_contexts(), ["loading", "decoding name"]);
+        let valid_up_to = match_peeled!(err => {
+            SingleError::Utf8(e) => e.valid_up_to(),
+        });
+        assert_eq!(valid_up_to, 0);
+    }
+
+    #[string_context]
+    #[derive(Error,Debug)]
+    enum ConfigError {
+        #[error("Missing key")]
+        MissingKey,
+    }
+
+    #[string_context]
+    #[derive(Error,Debug)]
+    enum AppError {
+        #[error("Config error")]
+        Config(#[from] ConfigError),
+        #[error("Startup error")]
+        Startup { source: std::fmt::Error },
+        #[error("Plain")]
+        Plain,
+    }
+
+    fn read_key() -> Result<(), ConfigError> {
+        let res: Result<(), ConfigError> = Err(ConfigError::MissingKey);
+        let res: Result<(), ConfigError> = res.with_context(|| "reading key port");
+        res.with_context(|| "parsing app.conf")
+    }
+
+    fn start_app() -> Result<(), AppError> {
+        let res: Result<(), AppError> = read_key().map_err(AppError::from);
+        res.with_context(|| "starting app")
+    }
+
+    #[test]
+    fn test_flatten_contexts() {
+        let err = start_app().unwrap_err();
+        assert_eq!(err.flatten_contexts(), ["starting app", "parsing app.conf", "reading key port"]);
+        // The own contexts only
+        assert_eq!(err.full_message(), "starting app: Config error");
+        // Through the shared trait
+        fn all_contexts<E: ContextLayers>(err: &E) -> String {
+            err.flatten_contexts().join(" / ")
+        }
+        assert_eq!(all_contexts(&err), "starting app / parsing app.conf / reading key port");
+
+        // The sources, which are not annotated enums, have no contexts
+        let err = AppError::with_ctx("booting", AppError::Startup { source: std::fmt::Error });
+        assert_eq!(err.flatten_contexts(), ["booting"]);
+        assert!(AppError::Plain.flatten_contexts().is_empty());
+        assert_eq!(AppError::from(ConfigError::MissingKey).flatten_contexts(), Vec::<&str>::new());
+    }
+
+    #[string_context]
+    #[derive(Error,Debug)]
+    enum DataError {
+        #[error("Empty")]
+        Empty,
+        #[error("Error 3: {0}")]
+        Error3(u32),
+        #[error("Range {0}..{1}")]
+        Range(i32, i32),
+        #[error("Named {code}")]
+        Named { code: u8, reason: String },
+        #[error("Custom {0}")]
+        #[skip_as]
+        Custom(u32),
+    }
+
+    impl DataError {
+        // Would clash with the generated accessor without #[skip_as]
+        fn as_custom(&self) -> Option<u32> {
+            match self.base_error() {
+                DataError::Custom(n) => Some(*n),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_variant_accessors() {
+        let res: Result<(), DataError> = Err(DataError::Error3(7));
+        let err = res.with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        assert_eq!(err.as_error3(), Some(&7));
+        assert_eq!(err.as_range(), None);
+        assert_eq!(DataError::Range(1, 2).as_range(), Some((&1, &2)));
+        let err = DataError::with_ctx("Context", DataError::Named { code: 3, reason: "bad".into() });
+        assert_eq!(err.as_named(), Some((&3, &"bad".to_string())));
+        assert_eq!(DataError::Empty.as_error3(), None);
+        assert_eq!(DataError::Custom(5).as_custom(), Some(5));
+    }
+
+    #[string_context(pretty_debug)]
+    #[derive(Error,Debug)]
+    enum DebugError {
+        #[error("Underflow")]
+        Underflow,
+        #[error("Overflow {0}")]
+        Overflow(i32),
+        #[error("Too far {distance}")]
+        TooFar { distance: u32 },
+    }
+
+    #[test]
+    fn test_pretty_debug() {
+        assert_eq!(format!("{:?}", DebugError::Underflow), "Underflow");
+        assert_eq!(format!("{:?}", DebugError::Overflow(1)), "Overflow(1)");
+        assert_eq!(format!("{:?}", DebugError::TooFar { distance: 2 }), "TooFar { distance: 2 }");
+
+        let res: Result<(),DebugError> = Err(DebugError::Underflow);
+        let one = res.with_context(|| "while loading config");
+        assert_eq!(
+            format!("{:?}", one.as_ref().unwrap_err()),
+            r#"Underflow (context: "while loading config")"#
+        );
+
+        let two = one.with_context(|| "starting").unwrap_err();
+        assert_eq!(
+            format!("{:?}", two),
+            r#"Underflow (context: "starting", "while loading config")"#
+        );
+        assert_eq!(
+            format!("{:#?}", two),
+            "Underflow\n  context: \"starting\"\n  context: \"while loading config\""
+        );
+    }
+
+    #[string_context("Io context: {0}", into_io, into_string)]
+    #[derive(Error,Debug)]
+    enum IoError {
+        #[error(transparent)]
+        Io(#[from] std::io::Error),
+        #[error("Parse error")]
+        Parse,
+    }
+
+    #[string_context]
+    #[derive(Error,Debug)]
+    enum OwnedDataError {
+        #[error("Invalid name {0}")]
+        Name(String),
+        #[error("Invalid bytes")]
+        Bytes { data: Vec<u8> },
+        #[error(transparent)]
+        Other(#[from] Box<dyn std::error::Error + Send + Sync>),
+    }
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+
+    #[test]
+    fn test_send_sync() {
+        assert_send_sync::<MyError>();
+        assert_send_sync::<IoError>();
+        assert_send_sync::<OwnedDataError>();
+
+        // The context survives sending to another thread and converting into anyhow
+        let res: Result<(), OwnedDataError> = Err(OwnedDataError::Name("x".into()));
+        let err = res.with_context(|| "validating").unwrap_err();
+        let err = std::thread::spawn(move || anyhow::Error::from(err)).join().unwrap();
+        assert_eq!(err.to_string(), "validating");
+        assert_eq!(err.root_cause().to_string(), "Invalid name x");
+        let res: Result<(), OwnedDataError> = Err(OwnedDataError::Bytes { data: vec![1] });
+        assert!(res.with_context(|| "decoding").unwrap_err().has_context());
+    }
+
+    #[test]
+    fn test_path_context() {
+        let res: Result<(), std::io::Error> = Err(std::io::ErrorKind::NotFound.into());
+        let res: Result<(), IoError> = res.with_path_context("/etc/app.conf");
+        assert_eq!(res.unwrap_err().context_str(), "/etc/app.conf");
+
+        let path = std::path::PathBuf::from("/etc/app.conf");
+        let res: Result<(), IoError> = Err(IoError::Parse).with_labeled_path_context("reading", &path);
+        assert_eq!(res.unwrap_err().full_message(), "reading /etc/app.conf: Parse error");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_path_context_invalid_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::path::Path::new(std::ffi::OsStr::from_bytes(b"/tmp/bad\xFF\xFEname"));
+        let res: Result<(), IoError> = Err(IoError::Parse).with_path_context(path);
+        assert_eq!(res.unwrap_err().context_str(), "/tmp/bad\u{FFFD}\u{FFFD}name");
+        let res: Result<(), IoError> = Err(IoError::Parse).with_labeled_path_context("opening", path);
+        assert_eq!(res.unwrap_err().context_str(), "opening /tmp/bad\u{FFFD}\u{FFFD}name");
+    }
+
+    #[test]
+    fn test_lossy_context() {
+        let res: Result<(), IoError> = Err(IoError::Parse).context_lossy_utf8(b"bad \xF0\x90 bytes");
+        assert_eq!(res.unwrap_err().context_str(), "bad \u{FFFD} bytes");
+        let res: Result<(), IoError> = Err(IoError::Parse).context_os(std::ffi::OsString::from("name"));
+        assert_eq!(res.unwrap_err().context_str(), "name");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_os_context_invalid_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+        let name = std::ffi::OsString::from_vec(b"report\xFF.txt".to_vec());
+        let res: Result<(), IoError> = Err(IoError::Parse).context_os(&name);
+        assert_eq!(res.unwrap_err().context_str(), "report\u{FFFD}.txt");
+    }
+
+    #[test]
+    fn test_context_with_error() {
+        let res: Result<(), std::io::Error> = Err(std::io::ErrorKind::NotFound.into());
+        let res: Result<(), IoError> = res.with_context_with(|e| format!("reading config ({:?})", e.kind()));
+        let err = res.unwrap_err();
+        assert_eq!(err.outermost_context(), Some("reading config (NotFound)"));
+        assert!(matches!(err.base_error(), IoError::Io(e) if e.kind() == std::io::ErrorKind::NotFound));
+
+        let res: Result<(), IoError> = Err(IoError::Parse);
+        let err = res.with_context_with(|e| format!("after {}", e)).unwrap_err();
+        assert_eq!(err.context_str(), "after Parse error");
+    }
+
+    fn open_config(kind: std::io::ErrorKind) -> Result<(), IoError> {
+        let res: Result<(), std::io::Error> = Err(kind.into());
+        res.map_to_context(|e| match e.kind() {
+            std::io::ErrorKind::InvalidData => (IoError::Parse, "parsing config".into()),
+            _ => (IoError::Io(e), "opening config".into()),
+        })
+    }
+
+    #[test]
+    fn test_map_to_context() {
+        let err = open_config(std::io::ErrorKind::InvalidData).unwrap_err();
+        assert_eq!(err.context_str(), "parsing config");
+        assert!(matches!(err.base_error(), IoError::Parse));
+
+        let err = open_config(std::io::ErrorKind::NotFound).unwrap_err();
+        assert_eq!(err.context_str(), "opening config");
+        assert!(matches!(err.base_error(), IoError::Io(e) if e.kind() == std::io::ErrorKind::NotFound));
+    }
+
+
+    #[derive(Error,Debug)]
+    #[error("Sub error {0}")]
+    struct SubError(i32);
+
+    #[string_context("Boxed context: {0}")]
+    #[derive(Error,Debug)]
+    enum BoxedSubError {
+        #[error(transparent)]
+        Sub(#[from] SubError),
+        #[error(transparent)]
+        Fmt(#[from] std::fmt::Error),
+        #[error("Boxed fmt error")]
+        BoxedFmt(#[from] Box<std::fmt::Error>),
+    }
+
+    fn boxed_sub(n: i32) -> Result<(), Box<SubError>> {
+        Err(Box::new(SubError(n)))
+    }
+
+    #[test]
+    fn test_boxed_source_errors() {
+        let err = boxed_sub(1).with_context(|| "Sub").unwrap_err();
+        assert_eq!(err.to_string(), "Boxed context: Sub");
+        assert!(matches!(err.base_error(), BoxedSubError::Sub(SubError(1))));
+
+        let res: Result<(), Box<SubError>> = boxed_sub(3);
+        let err = res.with_context_with(|e| format!("Sub {}", e.0)).unwrap_err();
+        assert_eq!(err.outermost_context(), Some("Sub 3"));
+
+        let res: Result<(), Box<BoxedSubError>> = Err(Box::new(SubError(2).into()));
+        assert_eq!(res.context("Own").unwrap_err().full_message(), "Own: Sub error 2");
+
+        // Boxes converted by `#[from]` are not unboxed
+        let res: Result<(), Box<std::fmt::Error>> = Err(Box::new(std::fmt::Error));
+        let err: BoxedSubError = res.with_context(|| "Fmt").unwrap_err();
+        assert!(err.is_boxed_fmt());
+    }
+
+    // The box is recognized by the last segment of its path
+    #[string_context("Boxed context: {0}")]
+    #[derive(Error,Debug)]
+    enum BoxedPathError {
+        #[error(transparent)]
+        Fmt(#[from] std::fmt::Error),
+        #[error("Boxed fmt error")]
+        BoxedFmt(#[from] std::boxed::Box<std::fmt::Error>),
+    }
+
+    #[test]
+    fn test_boxed_path_from() {
+        let res: Result<(), Box<std::fmt::Error>> = Err(Box::new(std::fmt::Error));
+        let err: BoxedPathError = res.with_context(|| "Fmt").unwrap_err();
+        assert!(err.is_boxed_fmt());
+    }
+
+    #[test]
+    fn test_into_io() {
+        let res: Result<(),std::io::Error> = Err(std::io::ErrorKind::NotFound.into());
+        let err: std::io::Error = res.with_context(|| "/etc/app.conf").unwrap_err().into();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "/etc/app.conf: entity not found");
+
+        let res: Result<(),IoError> = Err(IoError::Parse);
+        let err: std::io::Error = res.with_context(|| "Inner").with_context(|| "Outer").unwrap_err().into();
+        assert_eq!(err.kind(), std::io::ErrorKind::Other);
+        assert_eq!(err.to_string(), "Outer: Inner: Parse error");
+
+        // Without the context the original io error is returned
+        let err: std::io::Error = IoError::Io(std::io::Error::other("custom")).into();
+        assert_eq!(err.kind(), std::io::ErrorKind::Other);
+        assert_eq!(err.to_string(), "custom");
+    }
+
+    #[test]
+    fn test_into_string() {
+        let res: Result<(),IoError> = Err(IoError::Parse);
+        let s: String = res.with_context(|| "Inner").with_context(|| "Outer").unwrap_err().into();
+        assert_eq!(s, "Io context: Outer\n\nCaused by:\n    0: Io context: Inner\n    1: Parse error");
+    }
+
+    #[string_context]
+    #[derive(Error,Debug)]
+    enum PeeledError {
+        #[error("Underflow")]
+        Underflow,
+        #[error("Overflow {0}")]
+        Overflow(i32),
+        #[error("Too far {distance}")]
+        TooFar { distance: u32 },
+    }
+
+    fn classify(err: PeeledError) -> String {
+        match_peeled!(err => {
+            PeeledError::Underflow => "underflow".to_string(),
+            PeeledError::Overflow(n) if n > 10 => format!("big overflow {n}"),
+            PeeledError::Overflow(n) => format!("overflow {n}"),
+            PeeledError::TooFar { distance } => format!("too far {distance}"),
+        })
+    }
+
+    #[test]
+    fn test_match_peeled() {
+        assert_eq!(classify(PeeledError::Underflow), "underflow");
+        let res: Result<(),PeeledError> = Err(PeeledError::Overflow(42));
+        let err = res.with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        assert_eq!(classify(err), "big overflow 42");
+        let res: Result<(),PeeledError> = Err(PeeledError::Overflow(1));
+        assert_eq!(classify(res.with_context(|| "Context").unwrap_err()), "overflow 1");
+        assert_eq!(classify(PeeledError::TooFar { distance: 3 }), "too far 3");
+    }
+
+    #[string_context]
+    #[derive(Error,Debug)]
+    enum TransparentError {
+        #[error(transparent)]
+        Parse(#[from] std::num::ParseIntError),
+    }
+
+    #[test]
+    fn test_transparent() {
+        let res: Result<i32,_> = "abc".parse::<i32>();
+        let err = res.with_context(|| "Parsing port").unwrap_err();
+        let inner = "abc".parse::<i32>().unwrap_err();
+        assert_eq!(
+            err.display_chain().to_string(),
+            format!("Parsing port\n\nCaused by:\n    {}", inner)
+        );
+        // The source is the transparent variant, which forwards to the foreign error
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(source.to_string(), inner.to_string());
+        assert!(source.source().is_none());
+        assert!(matches!(err.base_error(), TransparentError::Parse(e) if *e == inner));
+    }
+
+    #[string_context(boxed_error = BoxedMessageError::Other)]
+    #[derive(Error,Debug)]
+    enum BoxedMessageError {
+        #[error("Other: {0}")]
+        Other(String),
+    }
+
+    #[string_context(boxed_error = Other)]
+    #[derive(Error,Debug)]
+    enum BoxedSourceError {
+        #[error("Other")]
+        Other(#[source] Box<dyn std::error::Error + Send + Sync>),
+    }
+
+    fn parse_boxed(s: &str) -> Result<i32, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(s.parse::<i32>()?)
+    }
+
+    #[test]
+    fn test_boxed_context() {
+        let inner = "abc".parse::<i32>().unwrap_err();
+
+        let res: Result<i32, Box<dyn std::error::Error>> = Err(Box::new(inner.clone()));
+        let err = res.with_boxed_context(|| "Parsing").unwrap_err();
+        assert_eq!(err.full_message(), format!("Parsing: Other: {}", inner));
+
+        let err: BoxedMessageError = parse_boxed("abc").with_boxed_context(|| "Parsing").unwrap_err();
+        assert_eq!(err.full_message(), format!("Parsing: Other: {}", inner));
+
+        let res: Result<_, BoxedSourceError> = parse_boxed("abc").with_boxed_context(|| "Parsing");
+        let (ctx, err) = res.unwrap_err().unwrap_context();
+        assert_eq!(ctx.as_deref(), Some("Parsing"));
+        // The original error is preserved as the source
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(source.downcast_ref::<std::num::ParseIntError>(), Some(&inner));
+    }
+
+    #[string_context("Report context: {0}", report)]
+    #[derive(Error,Debug)]
+    enum ReportError {
+        #[error("Underflow")]
+        Underflow,
+    }
+
+    fn report_main() -> Result<(), ReportErrorReport> {
+        let res: Result<(),ReportError> = Err(ReportError::Underflow);
+        res.with_context(|| "Inner").with_context(|| "Outer")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_report() {
+        assert_eq!(
+            format!("{:?}", report_main().unwrap_err()),
+            "Report context: Outer\n\nCaused by:\n    0: Report context: Inner\n    1: Underflow"
+        );
+        assert_eq!(format!("{:?}", ReportErrorReport::from(ReportError::Underflow)), "Underflow");
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_json_report() {
+        let res: Result<(), ReportError> = Err(ReportError::Underflow);
+        let err = res.context("reading \"app.conf\"").context("starting").unwrap_err();
+        let report = err.to_report();
+        assert_eq!(report.message, "Underflow");
+        assert_eq!(report.contexts, ["starting", "reading \"app.conf\""]);
+        assert_eq!(report.kind, "Underflow");
+        assert_eq!(
+            err.to_json(),
+            r#"{"message":"Underflow","contexts":["starting","reading \"app.conf\""],"kind":"Underflow"}"#
+        );
+        assert_eq!(ReportError::Underflow.to_json(), r#"{"message":"Underflow","contexts":[],"kind":"Underflow"}"#);
+    }
+
+    // The items of the crate are not imported here
+    mod nested {
+        use thiserror::Error;
+
+        #[crate::string_context("Nested context: {0}")]
+        #[derive(Error,Debug)]
+        pub enum NestedError {
+            #[error("Nested")]
+            Nested,
+        }
+
+        // Shadows the trait in the type namespace
+        #[crate::string_context]
+        #[derive(Error,Debug)]
+        pub enum AddErrorContext {
+            #[error("Link")]
+            Link,
+        }
+    }
+
+    // Shadows the enum of the same name in the `nested` module
+    #[string_context("Outer context: {0}")]
+    #[derive(Error,Debug)]
+    enum NestedError {
+        #[error("Outer")]
+        Outer,
+    }
+
+    #[test]
+    fn test_nested_scopes() {
+        let res: Result<(),nested::NestedError> = Err(nested::NestedError::Nested);
+        let err = res.with_context(|| "Inner").unwrap_err();
+        assert_eq!(err.to_string(), "Nested context: Inner");
+
+        let res: Result<(),NestedError> = Err(NestedError::Outer);
+        assert_eq!(res.with_context(|| "Inner").unwrap_err().to_string(), "Outer context: Inner");
+
+        let res: Result<(),nested::AddErrorContext> = Err(nested::AddErrorContext::Link);
+        let err = res.with_context(|| "Inner").unwrap_err();
+        assert_eq!(err.display_chain().to_string(), "Inner\n\nCaused by:\n    Link");
+
+        #[string_context("Local context: {0}")]
+        #[derive(Error,Debug)]
+        enum LocalError {
+            #[error("Local")]
+            Local,
+        }
+
+        let res: Result<(),LocalError> = Err(LocalError::Local);
+        let err = res.with_context(|| "Inner").unwrap_err();
+        assert_eq!(err.full_message(), "Inner: Local");
+        assert!(err.is_local());
+    }
+
+    #[string_context(message_variant = Other)]
+    #[derive(Error,Debug)]
+    enum MessageError {
+        #[error("Other: {0}")]
+        Other(String),
+    }
+
+    fn describe(n: i32) -> Result<i32, String> {
+        if n < 0 {
+            return Err(format!("negative value {}", n));
+        }
+        Ok(n)
+    }
+
+    fn check_message(n: i32) -> Result<i32, MessageError> {
+        if n == 0 {
+            return Err("zero value".into());
+        }
+        Ok(describe(n)?)
+    }
+
+    #[test]
+    fn test_message_variant() {
+        assert_eq!(check_message(0).unwrap_err().to_string(), "Other: zero value");
+        assert_eq!(check_message(-1).unwrap_err().to_string(), "Other: negative value -1");
+        assert_eq!(check_message(1).unwrap(), 1);
+
+        let err = describe(-2).with_context(|| "Checking").unwrap_err();
+        assert_eq!(err.full_message(), "Checking: Other: negative value -2");
+    }
+
+    #[string_context("Inner context: {0}")]
+    #[derive(Error,Debug)]
+    enum BridgeInnerError {
+        #[error("Underflow")]
+        Underflow,
+    }
+
+    #[string_context("Outer context: {0}", bridge = "BridgeInnerError")]
+    #[derive(Error,Debug)]
+    enum BridgeOuterError {
+        #[error("Inner")]
+        Inner(#[from] BridgeInnerError),
+    }
+
+    #[test]
+    fn test_bridge() {
+        let res: Result<(),BridgeInnerError> = Err(BridgeInnerError::Underflow);
+        let inner = AddErrorContext::<BridgeInnerError,_>::with_context(res, || "Inner");
+        let inner = AddErrorContext::<BridgeInnerError,_>::with_context(inner, || "Outer");
+        let err = BridgeOuterError::from(inner.unwrap_err());
+        assert_eq!(err.outermost_context(), Some("Outer"));
+        assert_eq!(err.innermost_context(), Some("Inner"));
+        assert_eq!(err.full_message(), "Outer: Inner: Inner");
+        assert!(matches!(err.base_error(), BridgeOuterError::Inner(BridgeInnerError::Underflow)));
+
+        // The inner error without context is converted as usual
+        let err = BridgeOuterError::from(BridgeInnerError::Underflow);
+        assert_eq!(err.context_str(), "");
+        assert!(err.is_inner());
+    }
+
+    #[string_context("Storage context: {0}")]
+    #[derive(Error,Debug)]
+    enum StorageError {
+        #[error("Disk full")]
+        DiskFull,
+    }
+
+    #[string_context("Service context: {0}")]
+    #[derive(Error,Debug)]
+    enum ServiceError {
+        #[error("Storage failed")]
+        Storage(#[source] StorageError),
+    }
+
+    // Written by hand, the contexts would be hidden inside the variant
+    impl From<StorageError> for ServiceError {
+        fn from(err: StorageError) -> Self {
+            ServiceError::Storage(err)
+        }
+    }
+
+    fn store() -> Result<(), StorageError> {
+        let res: Result<(), StorageError> = Err(StorageError::DiskFull);
+        res.with_context(|| "Writing block")
+    }
+
+    fn serve() -> Result<(), ServiceError> {
+        // The target is not inferred through `?`
+        store().with_context_into::<ServiceError, _>(|| "Saving upload")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_converted_context() {
+        let err = serve().unwrap_err();
+        assert_eq!(err.context_at(0), Some("Saving upload"));
+        assert_eq!(err.context_at(1), Some("Writing block"));
+        assert_eq!(err.full_message(), "Saving upload: Writing block: Storage failed");
+        assert!(matches!(err.base_error(), ServiceError::Storage(inner) if inner.context_at(0).is_none()));
+
+        let err: ServiceError = convert_context(store().unwrap_err());
+        assert_eq!(err.full_message(), "Writing block: Storage failed");
+        let res: Result<(), ServiceError> = store().with_context_into(|| "Saving upload");
+        assert_eq!(res.unwrap_err().context_at(1), Some("Writing block"));
+    }
+
+    #[test]
+    fn test_result_of_enum_itself() {
+        let res: Result<(),MyError> = Err(MyError::Error2);
+        let err = res.with_context(|| "Direct").unwrap_err();
+        assert_eq!(err.to_string(), "Custom context messag: Direct");
+        assert!(matches!(err.unwrap_context(), (Some(_), MyError::Error2)));
+
+        // The enum converts both into itself and into the bridged enum,
+        // so the target is ambiguous unless it is known from the context
+        let res: Result<(),BridgeInnerError> = Err(BridgeInnerError::Underflow);
+        let res: Result<(),BridgeInnerError> = res.with_context(|| "Inner");
+        let res: Result<(),BridgeOuterError> = res.with_context(|| "Outer");
+        assert_eq!(res.unwrap_err().full_message(), "Outer: Inner: Inner");
+
+        // Inherent methods are never ambiguous
+        let res: Result<(),BridgeInnerError> = Err(BridgeInnerError::Underflow);
+        let err = res.map_err(|e| e.attach_context("First").attach_context("Second")).unwrap_err();
+        assert_eq!(err.full_message(), "Second: First: Underflow");
+    }
+
+    fn render_with_timestamp(ctx: &str, source: &RenderedError, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match source.base_error() {
+            RenderedError::Timeout => write!(f, "[{{timestamp}}] {} (timed out)", ctx),
+            _ => write!(f, "[{{timestamp}}] {}", ctx),
+        }
+    }
+
+    #[string_context("", display_with = render_with_timestamp)]
+    #[derive(Error,Debug)]
+    enum RenderedError {
+        #[error("Timeout")]
+        Timeout,
+        #[error("Refused")]
+        Refused,
+    }
+
+    #[test]
+    fn test_display_with() {
+        let res: Result<(),RenderedError> = Err(RenderedError::Timeout);
+        let res: Result<(),RenderedError> = res.with_context(|| "Connecting");
+        let err = res.with_context(|| "Fetching").unwrap_err();
+        assert_eq!(err.to_string(), "[{timestamp}] Fetching (timed out)");
+        assert_eq!(
+            err.display_chain().to_string(),
+            "[{timestamp}] Fetching (timed out)\n\nCaused by:\n    0: [{timestamp}] Connecting (timed out)\n    1: Timeout"
+        );
+
+        // Base variants are rendered by thiserror
+        assert_eq!(RenderedError::Refused.to_string(), "Refused");
+        let err = RenderedError::Refused.attach_context("Connecting");
+        assert_eq!(err.to_string(), "[{timestamp}] Connecting");
+    }
+
+    #[string_context("Separated context: {0}", separator = " / ")]
+    #[derive(Error,Debug)]
+    enum SeparatorError {
+        #[error("Underflow")]
+        Underflow,
+    }
+
+    #[test]
+    fn test_separator() {
+        assert_eq!(SeparatorError::CONTEXT_SEPARATOR, " / ");
+        assert_eq!(MyError::CONTEXT_SEPARATOR, ": ");
+        let res: Result<(),SeparatorError> = Err(SeparatorError::Underflow);
+        let err = res.with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        assert_eq!(err.full_message(), "Outer / Inner / Underflow");
+        assert_eq!(err.message_chain().join(SeparatorError::CONTEXT_SEPARATOR), err.full_message());
+    }
+
+    #[string_context("Context for code {code:>4}: {0}")]
+    #[derive(Error,Debug)]
+    enum FieldTemplateError {
+        #[error("Error with code {code}")]
+        WithCode { code: u32 },
+        #[error("Error without code")]
+        WithoutCode,
+    }
+
+    #[test]
+    fn test_field_template() {
+        let res: Result<(),FieldTemplateError> = Err(FieldTemplateError::WithCode { code: 42 });
+        let one = res.with_context(|| "Inner");
+        assert_eq!(one.as_ref().unwrap_err().to_string(), "Context for code   42: Inner");
+        let two = one.with_context(|| "Outer").unwrap_err();
+        assert_eq!(two.to_string(), "Context for code   42: Outer");
+
+        // Missing field is rendered as an empty string
+        let res: Result<(),FieldTemplateError> = Err(FieldTemplateError::WithoutCode);
+        assert_eq!(res.with_context(|| "Inner").unwrap_err().to_string(), "Context for code     : Inner");
+    }
+
+    #[string_context]
+    #[derive(Error,Debug)]
+    enum ExitCodeError {
+        #[error("Not found")]
+        #[exit_code(3)]
+        NotFound,
+        #[error("Denied")]
+        #[exit_code(77)]
+        Denied,
+        #[error("Unknown")]
+        Unknown,
+    }
+
+    #[test]
+    fn test_exit_code() {
+        use std::process::ExitCode;
+        assert_eq!(ExitCodeError::NotFound.exit_code(), ExitCode::from(3));
+        assert_eq!(ExitCodeError::Unknown.exit_code(), ExitCode::from(1));
+        let res: Result<(),ExitCodeError> = Err(ExitCodeError::Denied);
+        let err = res.with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        assert_eq!(err.exit_code(), ExitCode::from(77));
+    }
+
+    #[string_context]
+    #[derive(Error,Debug)]
+    enum OwnCodesError {
+        #[error("Not found")]
+        NotFound,
+    }
+
+    // Without `#[exit_code]` and `#[error_code]` on the variants the enum is free to define its own method
+    impl OwnCodesError {
+        fn exit_code(&self) -> u8 {
+            42
+        }
+
+        fn code(&self) -> &'static str {
+            "OWN"
+        }
+    }
+
+    #[test]
+    fn test_own_codes() {
+        let res: Result<(),OwnCodesError> = Err(OwnCodesError::NotFound);
+        let err = res.with_context(|| "Inner").unwrap_err();
+        assert_eq!(err.exit_code(), 42);
+        assert_eq!(err.code(), "OWN");
+    }
+
+    #[string_context]
+    #[derive(Error,Debug)]
+    enum CodeError {
+        #[error("Not found")]
+        #[error_code("E1042")]
+        NotFound,
+        #[error("Denied")]
+        #[error_code("E2001")]
+        Denied,
+        #[error("Unknown")]
+        Unknown,
+    }
+
+    #[test]
+    fn test_error_code() {
+        assert_eq!(CodeError::NotFound.code(), Some("E1042"));
+        assert_eq!(CodeError::Unknown.code(), None);
+        let res: Result<(),CodeError> = Err(CodeError::Denied);
+        let err = res.with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        assert_eq!(err.code(), Some("E2001"));
+    }
+
+    #[test]
+    fn test_eager_context() {
+        let err: Result<(),MyError> = callme(1).context("Inner").with_context(|| "Outer");
+        let err = err.unwrap_err();
+        assert_eq!(err.innermost_context(), Some("Inner"));
+        assert_eq!(err.outermost_context(), Some("Outer"));
+
+        let name = String::from("config");
+        let err: Result<(),MyError> = callme(2).with_context(|| "Inner").context(format!("loading {}", name));
+        assert_eq!(err.unwrap_err().full_message(), "loading config: Inner: Error 2");
+        assert!(callme(42).context("Unused").is_ok());
+    }
+
+    #[test]
+    fn test_static_context() {
+        let res: Result<(),MyError> = Err(MyError::Error1);
+        let mut err = res.with_static_context("Static").unwrap_err();
+        assert_eq!(err.outermost_context(), Some("Static"));
+        assert_eq!(err.to_string(), "Custom context messag: Static");
+
+        // Static contexts could still be mutated
+        for ctx in err.contexts_mut() {
+            ctx.push('!');
+        }
+        assert_eq!(err.outermost_context(), Some("Static!"));
+    }
+
+    #[test]
+    fn test_context_args() {
+        let (row, col) = (3, 14);
+        let res: Result<(),MyError> = Err(MyError::Error1);
+        let err = res.with_context_args(|w| w(format_args!("row {} col {}", row, col))).unwrap_err();
+        assert_eq!(err.outermost_context(), Some("row 3 col 14"));
+
+        // Several calls of the callback append to the context
+        let res: Result<(),MyError> = Err(MyError::Error1);
+        let err = res.with_context_args(|w| {
+            w(format_args!("row {row}"));
+            w(format_args!(" col {col}"));
+        }).unwrap_err();
+        assert_eq!(err.outermost_context(), Some("row 3 col 14"));
+    }
+
+    #[string_context("Lookup context: {0}")]
+    #[derive(Error,Debug)]
+    enum LookupError {
+        #[error("Missing key")]
+        MissingKey,
+    }
+
+    #[test]
+    fn test_option_context() {
+        let map = std::collections::HashMap::from([("port", 8080)]);
+        let key = "host";
+        let err = map.get(key).ok_or_ctx(LookupError::MissingKey, || format!("key {key}")).unwrap_err();
+        assert_eq!(err.to_string(), "Lookup context: key host");
+        assert!(err.is_missing_key());
+
+        let calls = Cell::new(0);
+        let port = map.get("port").ok_or_ctx(LookupError::MissingKey, || {
+            calls.set(calls.get() + 1);
+            "key port"
+        });
+        assert_eq!(port.unwrap(), &8080);
+        assert_eq!(calls.get(), 0);
+    }
+
+    #[test]
+    fn test_context_if() {
+        let calls = Cell::new(0);
+        let expensive = || {
+            calls.set(calls.get() + 1);
+            "Row 1"
+        };
+        let err = callme(1).with_context_if(true, expensive).unwrap_err();
+        assert_eq!(err.outermost_context(), Some("Row 1"));
+        assert_eq!(calls.get(), 1);
+
+        let err = callme(1).with_context_if(false, expensive).unwrap_err();
+        assert!(matches!(err, MyError::Error1));
+        assert_eq!(calls.get(), 1);
+
+        let res: Result<(), std::io::Error> = Err(std::io::ErrorKind::NotFound.into());
+        let res: Result<(), IoError> = res.with_context_if(false, || "Unused");
+        assert!(matches!(res.unwrap_err(), IoError::Io(_)));
+    }
+
+    #[test]
+    fn test_or_context() {
+        let err = callme(1).or_context("Request failed").unwrap_err();
+        assert_eq!(err.to_string(), "Custom context messag: Request failed");
+        assert!(err.has_context());
+
+        let res: Result<(),MyError> = callme(2).with_context(|| "Reading row 5");
+        let err = res.or_context("Request failed").unwrap_err();
+        assert_eq!(err.to_string(), "Custom context messag: Reading row 5");
+        assert_eq!(err.full_message(), "Reading row 5: Error 2");
+
+        assert!(!MyError::Error1.has_context());
+        let err = MyError::Error1.or_context("First").or_context("Second");
+        assert_eq!(err.full_message(), "First: Error 1");
+    }
+
+    #[test]
+    fn test_context_at() {
+        let err: Result<(),MyError> = callme(1)
+            .with_context(|| "Inner")
+            .with_context(|| "Middle")
+            .with_context(|| "Outer");
+        let err = err.unwrap_err();
+        assert_eq!(err.context_at(0), Some("Outer"));
+        assert_eq!(err.context_at(1), Some("Middle"));
+        assert_eq!(err.context_at(2), Some("Inner"));
+        assert_eq!(err.context_at(3), None);
+        assert_eq!(err.context_at(usize::MAX), None);
+        assert_eq!(MyError::Error1.context_at(0), None);
+    }
+
+    #[test]
+    fn test_context_matches() {
+        let err: Result<(),MyError> = callme(1)
+            .with_context(|| "Inner")
+            .with_context(|| "Request timeout")
+            .with_context(|| "Outer");
+        let err = err.unwrap_err();
+        let calls = Cell::new(0);
+        assert!(err.context_matches(|ctx| {
+            calls.set(calls.get() + 1);
+            ctx.contains("timeout")
+        }));
+        // The innermost context is not checked
+        assert_eq!(calls.get(), 2);
+        assert!(!err.context_matches(|ctx| ctx.is_empty()));
+        assert!(!MyError::Error1.context_matches(|_| true));
+    }
+
+    #[test]
+    fn test_ctx_macro() {
+        let path = std::path::Path::new("/tmp/data");
+        let err = callme(1).with_context(ctx!("reading {}", path.display())).unwrap_err();
+        assert_eq!(err.context_str(), "reading /tmp/data");
+
+        let id = 5;
+        let err = callme(2).with_context(ctx!("item {id} of {total}", total = 10)).unwrap_err();
+        assert_eq!(err.context_str(), "item 5 of 10");
+
+        // The arguments are not evaluated on success
+        let calls = Cell::new(0);
+        let count = || {
+            calls.set(calls.get() + 1);
+            calls.get()
+        };
+        callme(42).with_context(ctx!("call {}", count())).unwrap();
+        assert_eq!(calls.get(), 0);
+        let err = callme(3).or_else_context(ctx!("call {}", count())).unwrap_err();
+        assert_eq!(err.context_str(), "call 1");
+    }
+
+    #[test]
+    fn test_ctx_macro_with_result() {
+        let calls = Cell::new(0);
+        let count = || {
+            calls.set(calls.get() + 1);
+            calls.get()
+        };
+        let res: Result<(),MyError> = ctx!(callme(42), "call {}", count());
+        assert!(res.is_ok());
+        assert_eq!(calls.get(), 0);
+
+        let res: Result<(),MyError> = ctx!(callme(1), "call {}", count());
+        assert_eq!(res.unwrap_err().context_str(), "call 1");
+        let name = "row";
+        let res: Result<(),MyError> = ctx!(callme(2), "{name} {n}", n = 7);
+        assert_eq!(res.unwrap_err().full_message(), "row 7: Error 2");
+    }
+
+    #[test]
+    fn test_dyn_context() {
+        let suppliers: Vec<Box<dyn Fn() -> String>> = vec![
+            Box::new(|| "Inner".to_string()),
+            Box::new(|| format!("Middle {}", 2)),
+            Box::new(|| "Outer".to_string()),
+        ];
+        let mut res = callme(1);
+        for f in &suppliers {
+            res = res.with_context_dyn(f.as_ref());
+        }
+        assert_eq!(res.unwrap_err().full_message(), "Outer: Middle 2: Inner: Error 1");
+        assert!(callme(42).with_context_dyn(&|| unreachable!()).is_ok());
+    }
+
+    #[string_context("Mapped context: {0}")]
+    #[derive(Error,Debug)]
+    enum MappedError {
+        #[error("Invalid number {0}")]
+        InvalidNumber(String),
+    }
+
+    #[test]
+    fn test_map_err_context() {
+        let res = "abc".parse::<i32>();
+        let err = res.map_err_context(|e| MappedError::InvalidNumber(e.to_string()), || "Parsing").unwrap_err();
+        assert_eq!(err.to_string(), "Mapped context: Parsing");
+        assert!(matches!(err.base_error(), MappedError::InvalidNumber(msg) if msg.contains("invalid digit")));
+
+        let calls = Cell::new(0);
+        let res = "42".parse::<i32>().map_err_context(
+            |e| {
+                calls.set(calls.get() + 1);
+                MappedError::InvalidNumber(e.to_string())
+            },
+            || {
+                calls.set(calls.get() + 1);
+                "Parsing"
+            },
+        );
+        assert_eq!(res.unwrap(), 42);
+        assert_eq!(calls.get(), 0);
+    }
+
+    use futures::executor::block_on;
+
+    #[cfg(feature = "async")]
+    async fn fetch(n: i32) -> Result<i32, IoError> {
+        // Yield once to check the wrapper with the pending future
+        let mut yielded = false;
+        std::future::poll_fn(|cx| {
+            if yielded {
+                std::task::Poll::Ready(())
+            } else {
+                yielded = true;
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+        }).await;
+        match n {
+            0 => Err(IoError::Parse),
+            n => Ok(n),
+        }
+    }
+
+    #[cfg(feature = "async")]
+    async fn fetch_all(url: &str) -> Result<i32, IoError> {
+        let n = fetch(1).with_context(|| format!("fetching {url}")).await?;
+        let m = fetch(n - 1).with_context(|| format!("fetching {url} again")).await?;
+        Ok(m)
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_future_context() {
+        let err = block_on(fetch_all("example.com")).unwrap_err();
+        assert_eq!(err.full_message(), "fetching example.com again: Parse error");
+
+        let calls = Cell::new(0);
+        let res: Result<i32, IoError> = block_on(fetch(5).with_context(|| {
+            calls.set(calls.get() + 1);
+            "Unused"
+        }));
+        assert_eq!(res.unwrap(), 5);
+        assert_eq!(calls.get(), 0);
+
+        fn assert_send<T: Send>(_: &T) {}
+        let fut: ContextFuture<_, _, IoError> = fetch(1).with_context(|| "Send");
+        assert_send(&fut);
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_future_context_pinning() {
+        fn assert_unpin<T: Unpin>(_: &T) {}
+        let res: Result<(), IoError> = Err(IoError::Parse);
+        let fut: ContextFuture<_, _, IoError> = std::future::ready(res).with_context(|| "Ready");
+        assert_unpin(&fut);
+        assert_eq!(block_on(fut).unwrap_err().full_message(), "Ready: Parse error");
+
+        // Futures, which can't be moved after polling, are pinned in place
+        let fut = async {
+            let _pinned = std::marker::PhantomPinned;
+            fetch(0).await
+        };
+        let err = block_on(fut.with_context(|| "Pinned")).unwrap_err();
+        assert_eq!(err.full_message(), "Pinned: Parse error");
+    }
+
+    #[test]
+    #[cfg(feature = "streams")]
+    fn test_stream_item_context() {
+        use futures::StreamExt;
+
+        let items: Vec<Result<i32, IoError>> = vec![Ok(1), Err(IoError::Parse), Ok(3), Err(IoError::Parse)];
+        let calls = Cell::new(0);
+        let stream = futures::stream::iter(items).with_item_context(|| {
+            calls.set(calls.get() + 1);
+            format!("record {}", calls.get())
+        });
+        let res: Vec<Result<i32, IoError>> = block_on(stream.collect());
+        assert_eq!(calls.get(), 2);
+        assert_eq!(res[0].as_ref().unwrap(), &1);
+        assert_eq!(res[1].as_ref().unwrap_err().full_message(), "record 1: Parse error");
+        assert_eq!(res[2].as_ref().unwrap(), &3);
+        assert_eq!(res[3].as_ref().unwrap_err().full_message(), "record 2: Parse error");
+
+        fn assert_send_unpin<T: Send + Unpin>(_: &T) {}
+        let res: Vec<Result<i32, std::io::Error>> = vec![Err(std::io::ErrorKind::NotFound.into())];
+        let stream: ContextStream<_, _, IoError> = futures::stream::iter(res).with_item_context(|| "Reading");
+        assert_send_unpin(&stream);
+        assert_eq!(futures::Stream::size_hint(&stream), (1, Some(1)));
+    }
+
+    #[test]
+    fn test_inspect_context() {
+        let logged = std::cell::RefCell::new(Vec::new());
+        let res: Result<(),MyError> = callme(1)
+            .with_context(|| "Inner")
+            .inspect_context(|ctx| logged.borrow_mut().push(ctx.to_string()))
+            .with_context(|| "Outer");
+        let err = res.inspect_context(|ctx| logged.borrow_mut().push(ctx.to_string())).unwrap_err();
+        assert_eq!(*logged.borrow(), ["Inner", "Outer"]);
+        assert_eq!(err.full_message(), "Outer: Inner: Error 1");
+
+        let calls = Cell::new(0);
+        let err = callme(2).inspect_context(|_| calls.set(calls.get() + 1)).unwrap_err();
+        assert!(matches!(err.inspect_context(|_| calls.set(calls.get() + 1)), MyError::Error2));
+        assert!(callme(42).inspect_context(|_| calls.set(calls.get() + 1)).is_ok());
+        assert_eq!(calls.get(), 0);
+    }
+
+    #[test]
+    fn test_iterator_context() {
+        let processed = Cell::new(0);
+        let parse = |s: &&str| {
+            processed.set(processed.get() + 1);
+            s.parse::<i32>().map_err(|_| MyError::Error1)
+        };
+        let res: Result<Vec<i32>, MyError> = ["1", "2", "x", "4"]
+            .into_iter()
+            .try_collect_with_context(parse, |i, s| format!("entry {i} ({s})"));
+        let err = res.unwrap_err();
+        assert_eq!(err.context_str(), "entry 2 (x)");
+        assert!(matches!(err.base_error(), MyError::Error1));
+        // The items after the failing one are not processed
+        assert_eq!(processed.get(), 3);
+
+        let calls = Cell::new(0);
+        let res: Result<Vec<i32>, MyError> = ["1", "2"].into_iter().try_collect_with_context(parse, |i, _| {
+            calls.set(calls.get() + 1);
+            format!("entry {i}")
+        });
+        assert_eq!(res.unwrap(), [1, 2]);
+        assert_eq!(calls.get(), 0);
+    }
+
+    fn load_settings(path: &str) -> Result<(), MyError> {
+        callme(1).with_context(fn_ctx!("loading {path}"))?;
+        Ok(())
+    }
+
+    #[err_context("fetching {}", function_name!())]
+    async fn fetch_settings() -> Result<(), MyError> {
+        callme(2)
+    }
+
+    #[test]
+    fn test_fn_context() {
+        let err = load_settings("app.conf").unwrap_err();
+        assert_eq!(err.context_str(), "loading app.conf (in thiserror_string_context::tests::load_settings)");
+
+        let name = Some(1).and_then(|_| Some(2).map(|_| function_name!())).unwrap();
+        assert_eq!(name, "thiserror_string_context::tests::test_fn_context");
+
+        let err = block_on(fetch_settings()).unwrap_err();
+        assert_eq!(err.context_str(), "fetching thiserror_string_context::tests::fetch_settings");
+    }
+
+    #[test]
+    fn test_context_tap() {
+        let mut reported = Vec::new();
+        let err = callme(1)
+            .with_context_tap(|| "Tapped", |e| reported.push(e.full_message()))
+            .unwrap_err();
+        assert_eq!(reported, ["Tapped: Error 1"]);
+        assert_eq!(err.context_str(), "Tapped");
+
+        let calls = Cell::new(0);
+        callme(42).with_context_tap(|| {
+            calls.set(calls.get() + 1);
+            "Unused"
+        }, |_| calls.set(calls.get() + 1)).unwrap();
+        assert_eq!(calls.get(), 0);
+    }
+
+    #[string_context("Severity context: {0}", severity_prefix)]
+    #[derive(Error,Debug)]
+    #[cfg(feature = "severity")]
+    enum SeverityError {
+        #[error("Timeout")]
+        Timeout,
+    }
+
+    #[test]
+    #[cfg(feature = "severity")]
+    fn test_severity() {
+        let err = callme(1).with_context(|| "Default").unwrap_err();
+        assert_eq!(err.severity(), Some(Severity::Error));
+        assert_eq!(err.to_string(), "Custom context messag: Default");
+        let err = callme(1).with_context_severity(Severity::Warning, || "Retrying").unwrap_err();
+        assert_eq!(err.severity(), Some(Severity::Warning));
+        assert_eq!(err.to_string(), "Custom context messag: Retrying");
+        assert_eq!(MyError::Error1.severity(), None);
+
+        let res: Result<(), SeverityError> = Err(SeverityError::Timeout);
+        let res: Result<(), SeverityError> = res.with_context_severity(Severity::Fatal, || "Connecting");
+        let err = res.with_context_severity(Severity::Warning, || "Syncing").unwrap_err();
+        assert_eq!(err.to_string(), "[warning] Severity context: Syncing");
+        assert_eq!(err.severity(), Some(Severity::Warning));
+        assert_eq!(err.full_message(), "Syncing: Connecting: Timeout");
+        let res: Result<(), SeverityError> = Err(SeverityError::Timeout);
+        assert_eq!(res.with_context(|| "Plain").unwrap_err().to_string(), "[error] Severity context: Plain");
+    }
+
+    #[string_context("Traced context: {0}", trace_level = WARN)]
+    #[derive(Error,Debug)]
+    #[cfg(feature = "tracing")]
+    enum TracedError {
+        #[error("Offline")]
+        Offline,
+    }
+
+    // Subscriber recording the level and the fields of the events
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct EventRecorder(std::sync::Arc<std::sync::Mutex<Vec<(tracing::Level, String, String)>>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for EventRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct Fields(String, String);
+
+            impl tracing::field::Visit for Fields {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    match field.name() {
+                        "context" => self.0 = format!("{value:?}"),
+                        "error" => self.1 = format!("{value:?}"),
+                        _ => {}
+                    }
+                }
+            }
+
+            let mut fields = Fields(String::new(), String::new());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push((*event.metadata().level(), fields.0, fields.1));
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn test_tracing_event() {
+        let recorder = EventRecorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let res: Result<(), TracedError> = Err(TracedError::Offline);
+            let res: Result<(), TracedError> = res.with_context(|| "Inner");
+            let _ = res.context("Outer");
+            // Nothing is emitted on success
+            let ok: Result<(), TracedError> = Ok(());
+            let _ = ok.context("Unused");
+            let _ = callme(1).with_context(|| "Default level");
+        });
+        let events = recorder.0.lock().unwrap();
+        assert_eq!(*events, [
+            (tracing::Level::WARN, "Inner".to_string(), "Offline".to_string()),
+            (tracing::Level::WARN, "Outer".to_string(), "Traced context: Inner".to_string()),
+            (tracing::Level::DEBUG, "Default level".to_string(), "Error 1".to_string()),
+        ]);
+    }
+
+    #[string_context("Job context: {0}")]
+    #[derive(Error,Debug,PartialEq,serde::Serialize,serde::Deserialize)]
+    #[cfg(feature = "serde")]
+    enum JobError {
+        #[error("Missing job {id}")]
+        Missing { id: u32 },
+        #[error("Timeout")]
+        Timeout,
+    }
+
+    #[string_context("Tagged job context: {0}")]
+    #[derive(Error,Debug,PartialEq,serde::Serialize,serde::Deserialize)]
+    #[serde(tag = "type")]
+    #[cfg(feature = "serde")]
+    enum TaggedJobError {
+        #[error("Missing job {id}")]
+        Missing { id: u32 },
+        #[error("Timeout")]
+        Timeout,
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_round_trip() {
+        let res: Result<(), JobError> = Err(JobError::Missing { id: 7 });
+        let res: Result<(), JobError> = res.with_context(|| "loading");
+        let err = res.context("saving").unwrap_err();
+        let json = serde_json::to_string(&err).unwrap();
+        assert_eq!(json, r#"{"WithContext":{"context":"saving","error":{"WithContext":{"context":"loading","error":{"Missing":{"id":7}}}}}}"#);
+        let back: JobError = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, err);
+        assert_eq!(back.full_message(), "saving: loading: Missing job 7");
+
+        // Errors without the context are serialized as usual
+        assert_eq!(serde_json::to_string(&JobError::Timeout).unwrap(), r#""Timeout""#);
+        assert_eq!(serde_json::from_str::<JobError>(r#""Timeout""#).unwrap(), JobError::Timeout);
+    }
+
+    #[string_context(external)]
+    #[derive(Error,Debug,serde::Serialize,serde::Deserialize)]
+    #[cfg(feature = "serde")]
+    enum ExternalJobError {
+        #[error("Timeout")]
+        Timeout,
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_skips_external() {
+        let res: Result<(), VendorError> = Err(VendorError(3));
+        let err: ExternalJobError = res.with_external_context(|| "calling vendor").unwrap_err();
+        assert!(serde_json::to_string(&err).is_err());
+        let err = ExternalJobError::with_ctx("retrying", err);
+        assert!(serde_json::to_string(&err).is_err());
+        // The hidden variant is not accepted when deserializing
+        assert!(serde_json::from_str::<ExternalJobError>(r#"{"__External":null}"#).is_err());
+        assert!(matches!(serde_json::from_str(r#""Timeout""#), Ok(ExternalJobError::Timeout)));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_internally_tagged_round_trip() {
+        let res: Result<(), TaggedJobError> = Err(TaggedJobError::Missing { id: 7 });
+        let res: Result<(), TaggedJobError> = res.with_context(|| "loading");
+        let err = res.context("saving").unwrap_err();
+        let json = serde_json::to_string(&err).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"WithContext","context":"saving","error":{"type":"WithContext","context":"loading","error":{"type":"Missing","id":7}}}"#
+        );
+        let back: TaggedJobError = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, err);
+        assert_eq!(back.to_string(), "Tagged job context: saving");
+
+        let err = TaggedJobError::with_ctx("waiting", TaggedJobError::Timeout);
+        let json = serde_json::to_string(&err).unwrap();
+        assert_eq!(json, r#"{"type":"WithContext","context":"waiting","error":{"type":"Timeout"}}"#);
+        assert_eq!(serde_json::from_str::<TaggedJobError>(&json).unwrap(), err);
+    }
+
+    #[string_context("Diagnosed context: {0}")]
+    #[derive(Error,Debug,miette::Diagnostic)]
+    #[cfg(feature = "miette")]
+    enum DiagnosedError {
+        #[error("Bad config")]
+        #[diagnostic(code(app::bad_config), help("check the config"))]
+        BadConfig,
+    }
+
+    #[test]
+    #[cfg(feature = "miette")]
+    fn test_miette_help() {
+        use miette::Diagnostic;
+        let res: Result<(), DiagnosedError> = Err(DiagnosedError::BadConfig);
+        let res: Result<(), DiagnosedError> = res.with_context(|| "reading app.conf");
+        let err = res.context("starting").unwrap_err();
+        assert_eq!(err.help().unwrap().to_string(), "starting");
+        // The diagnostic of the base variant is unchanged
+        let base = err.base_error();
+        assert_eq!(base.help().unwrap().to_string(), "check the config");
+        assert_eq!(Diagnostic::code(base).unwrap().to_string(), "app::bad_config");
+    }
+
+    #[test]
+    fn test_try_each_context() {
+        let mut processed = Vec::new();
+        let err = MyError::try_each_with_context([42, 2, 1], |n| {
+            processed.push(n);
+            callme(n)
+        }).unwrap_err();
+        assert_eq!(err.full_message(), "item 1: Error 2");
+        assert_eq!(processed, [42, 2]);
+
+        let res: Result<(), MyError> = (0..3).try_each_with_context(|_| callme(42));
+        assert!(res.is_ok());
+    }
+
+    fn sum_numbers(a: &str, b: &str) -> Result<i32, MyError> {
+        let parse = |s: &str| s.parse::<i32>().map_err(|_| MyError::Error1);
+        run_with_context(|| format!("summing {a} and {b}"), || -> Result<_, MyError> {
+            let a = parse(a)?;
+            let b = parse(b)?;
+            Ok(a + b)
+        })
+    }
+
+    #[test]
+    fn test_run_with_context() {
+        assert_eq!(sum_numbers("1", "2").unwrap(), 3);
+        let err = sum_numbers("1", "x").unwrap_err();
+        assert_eq!(err.context_str(), "summing 1 and x");
+        assert!(matches!(err.base_error(), MyError::Error1));
+
+        let res = context_block!(MyError, {
+            callme(42)?;
+            callme(3)?;
+            Ok(1)
+        }, "block {}", 1);
+        assert_eq!(res.unwrap_err().full_message(), "block 1: Error 3");
+        let res = context_block!(MyError, { callme(42)?; Ok(2) }, "unused");
+        assert_eq!(res.unwrap(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "thread-name")]
+    fn test_context_thread() {
+        let err = std::thread::Builder::new()
+            .name("worker-1".to_string())
+            .spawn(|| callme(1).with_context(|| "In worker").unwrap_err())
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(err.context_thread(), Some("worker-1"));
+
+        let err = std::thread::spawn(|| callme(1).with_context(|| "Unnamed").unwrap_err()).join().unwrap();
+        assert_eq!(err.context_thread(), None);
+        assert_eq!(MyError::Error1.context_thread(), None);
+    }
+
+    fn reconcile(n: i32) -> Result<(), MyError> {
+        let _scope = MyError::context_scope(format!("reconciling account {n}"));
+        {
+            let _scope = MyError::context_scope("loading balance");
+            callme(42).with_scoped_context()?;
+        }
+        callme(n).with_scoped_context()
+    }
+
+    fn load_account(n: i32) -> Result<(), MyError> {
+        let _scope = context_scope("loading");
+        scoped_context!(callme(n));
+        Ok(())
+    }
+
+    fn sync_account(n: i32) -> Result<(), MyError> {
+        let _scope = context_scope(format!("syncing account {n}"));
+        // The contexts are already added by the nested scope
+        load_account(n)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_scoped_context_macro() {
+        let _scope = context_scope("batch");
+        assert!(sync_account(42).is_ok());
+        assert_eq!(sync_account(2).unwrap_err().full_message(), "batch: syncing account 2: loading: Error 2");
+        let err = (|| -> Result<(), MyError> {
+            let _scope = context_scope("inner");
+            scoped_context!(callme(1));
+            Ok(())
+        })().unwrap_err();
+        assert_eq!(err.message_chain(), ["batch", "inner", "Error 1"]);
+    }
+
+    #[test]
+    fn test_scoped_context() {
+        let handles: Vec<_> = [1, 2].into_iter().map(|n| std::thread::spawn(move || {
+            let _scope = context_scope(format!("thread {n}"));
+            reconcile(n).unwrap_err().full_message()
+        })).collect();
+        let messages: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(messages, [
+            "thread 1: reconciling account 1: Error 1",
+            "thread 2: reconciling account 2: Error 2",
+        ]);
+
+        // Nested scopes and dropped guards
+        let _outer = context_scope("outer");
+        let err = {
+            let _inner = context_scope("inner");
+            callme(1).with_scoped_context().unwrap_err()
+        };
+        assert_eq!(err.full_message(), "outer: inner: Error 1");
+        let err = callme(1).with_scoped_context().unwrap_err();
+        assert_eq!(err.full_message(), "outer: Error 1");
+        drop(_outer);
+        assert!(!callme(1).with_scoped_context().unwrap_err().has_context());
+        with_context_scopes(|scopes| assert!(scopes.is_empty()));
+
+        // Unwinding pops the scope
+        let _ = std::panic::catch_unwind(|| {
+            let _scope = context_scope("panicking");
+            panic!("unwinding");
+        });
+        with_context_scopes(|scopes| assert!(scopes.is_empty()));
+    }
+
+    #[err_context("loading profile for {user_id} from {}", source.to_uppercase())]
+    fn load_profile(user_id: i32, source: &str) -> Result<i32, MyError> {
+        if user_id < 0 {
+            return Err(MyError::Error3);
+        }
+        callme(user_id)?;
+        Ok(user_id)
+    }
+
+    #[err_context("fetching profile {user_id}")]
+    async fn fetch_profile(user_id: i32) -> Result<i32, MyError> {
+        std::future::ready(callme(user_id)).await?;
+        Ok(user_id)
+    }
+
+    #[test]
+    fn test_err_context() {
+        assert_eq!(load_profile(42, "db").unwrap(), 42);
+        let err = load_profile(1, "db").unwrap_err();
+        assert_eq!(err.full_message(), "loading profile for 1 from DB: Error 1");
+        let err = load_profile(-1, "cache").unwrap_err();
+        assert_eq!(err.full_message(), "loading profile for -1 from CACHE: Error 3");
+
+        assert_eq!(block_on(fetch_profile(42)).unwrap(), 42);
+        let err = block_on(fetch_profile(2)).unwrap_err();
+        assert_eq!(err.full_message(), "fetching profile 2: Error 2");
+    }
+
+    #[test]
+    fn test_strip_context() {
+        let err = MyError::Error2.strip_context();
+        assert!(err.unwrap_context().0.is_none());
+
+        let err = callme(1).with_context(|| "Context").unwrap_err().strip_context();
+        assert!(matches!(err, MyError::Error1));
+
+        let err: Result<(),MyError> = callme(3).with_context(|| "Inner").with_context(|| "Outer");
+        let err = err.unwrap_err().strip_context();
+        assert!(matches!(err, MyError::Error3));
+        assert_eq!(err.to_string(), "Error 3");
+    }
+
+    #[test]
+    fn test_variant_eq() {
+        let err1 = callme(1).with_context(|| "Context").unwrap_err();
+        assert!(err1.variant_eq(&MyError::Error1));
+        assert!(MyError::Error1.variant_eq(&err1));
+        assert!(!err1.variant_eq(&MyError::Error2));
+
+        let err2: Result<(),MyError> = Err(err1).with_context(|| "Outer context");
+        let err2 = err2.unwrap_err();
+        assert!(err2.variant_eq(&MyError::Error1));
+        assert!(!err2.variant_eq(&callme(3).with_context(|| "Context").unwrap_err()));
+    }
+
+    #[test]
+    fn test_or_else_context() {
+        let (ctx,err) = callme(1).or_else_context(|| "Default").unwrap_err().unwrap_context();
+        assert_eq!(ctx.as_deref(), Some("Default"));
+        assert!(matches!(err, MyError::Error1));
+
+        let res = callme(2).with_context(|| "Original");
+        let (ctx,err) = res.or_else_context(|| -> &str { panic!("Must not be called") })
+            .unwrap_err()
+            .unwrap_context();
+        assert_eq!(ctx.as_deref(), Some("Original"));
+        assert!(matches!(err, MyError::Error2));
+    }
+
+    #[string_context]
+    #[derive(Error,Debug,PartialEq,Clone)]
+    enum EqError {
+        #[error("Overflow {0}")]
+        Overflow(i32),
+    }
+
+    #[test]
+    fn test_eq_ignoring_context() {
+        let res: Result<(),EqError> = Err(EqError::Overflow(1));
+        let inner = res.with_context(|| "Inner");
+        let nested = inner.with_context(|| "Middle").with_context(|| "Outer").unwrap_err();
+        assert!(nested.eq_ignoring_context(&EqError::Overflow(1)));
+        assert!(!nested.eq_ignoring_context(&EqError::Overflow(2)));
+        assert_ne!(nested, EqError::Overflow(1));
+
+        let res: Result<(),EqError> = Err(EqError::Overflow(1));
+        let other = res.with_context(|| "Different").unwrap_err();
+        assert!(nested.eq_ignoring_context(&other));
+        assert!(other.eq_ignoring_context(&nested));
+    }
+
+    #[test]
+    fn test_full_message() {
+        assert_eq!(MyError::Error1.full_message(), "Error 1");
+
+        let one = callme(1).with_context(|| "parsing [server] section");
+        assert_eq!(one.as_ref().unwrap_err().full_message(), "parsing [server] section: Error 1");
+
+        let three = one.with_context(|| "loading config").with_context(|| "starting server");
+        assert_eq!(
+            three.unwrap_err().full_message(),
+            "starting server: loading config: parsing [server] section: Error 1"
+        );
+    }
+
+    #[test]
+    fn test_display_chain() {
+        assert_eq!(MyError::Error1.display_chain().to_string(), "Error 1");
+
+        let one = callme(1).with_context(|| "Inner");
+        assert_eq!(
+            one.as_ref().unwrap_err().display_chain().to_string(),
+            "Custom context messag: Inner\n\nCaused by:\n    Error 1"
+        );
+
+        let two = one.with_context(|| "Outer").unwrap_err();
+        assert_eq!(
+            Chain(&two).to_string(),
+            "Custom context messag: Outer\n\nCaused by:\n    0: Custom context messag: Inner\n    1: Error 1"
+        );
+    }
+
+    #[test]
+    fn test_context_chain_display() {
+        let err = callme(2)
+            .with_context(|| "Reading file")
+            .with_context(|| "Loading config")
+            .unwrap_err();
+        assert_eq!(
+            err.context_chain_display(),
+            "Custom context messag: Loading config\n\nCaused by:\n    0: Custom context messag: Reading file\n    1: Error 2"
+        );
+    }
+
+    #[test]
+    fn test_outermost_innermost_context() {
+        assert_eq!(MyError::Error1.outermost_context(), None);
+        assert_eq!(MyError::Error1.innermost_context(), None);
+
+        let one = callme(1).with_context(|| "Inner");
+        let err = one.as_ref().unwrap_err();
+        assert_eq!(err.outermost_context(), Some("Inner"));
+        assert_eq!(err.innermost_context(), Some("Inner"));
+
+        let three = one.with_context(|| "Middle").with_context(|| "Outer").unwrap_err();
+        assert_eq!(three.outermost_context(), Some("Outer"));
+        assert_eq!(three.innermost_context(), Some("Inner"));
+    }
+
+    #[test]
+    fn test_context_str_or() {
+        assert_eq!(MyError::Error1.context_str(), "");
+        assert_eq!(MyError::Error1.context_str_or("<no context>"), "<no context>");
+
+        let err = callme(1).with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        assert_eq!(err.context_str(), "Outer");
+        assert_eq!(err.context_str_or("<no context>"), "Outer");
+    }
+
+    #[test]
+    fn test_contexts_mut() {
+        let mut err = callme(1)
+            .with_context(|| "inner")
+            .with_context(|| "middle")
+            .with_context(|| "outer")
+            .unwrap_err();
+        for ctx in err.contexts_mut() {
+            *ctx = ctx.to_uppercase();
+        }
+        assert_eq!(err.full_message(), "OUTER: MIDDLE: INNER: Error 1");
+        assert_eq!(MyError::Error1.contexts_mut().count(), 0);
+    }
+
+    #[test]
+    fn test_fmt_context() {
+        assert_eq!(format!("[{}]", MyError::Error1.fmt_context()), "[]");
+        let err = callme(1).with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        assert_eq!(format!("[{}]", err.fmt_context()), "[Outer]");
+    }
+
+    #[test]
+    fn test_with_ctx() {
+        let (ctx,err) = MyError::with_ctx("Manual", MyError::Error3).unwrap_context();
+        assert_eq!(ctx.as_deref(), Some("Manual"));
+        assert!(matches!(err, MyError::Error3));
+
+        // Stacking on the error with context
+        let inner = callme(3).with_context(|| "Inner").unwrap_err();
+        let err = MyError::with_ctx(String::from("Outer"), inner);
+        assert_eq!(err.full_message(), "Outer: Inner: Error 3");
+        assert_eq!(
+            err.to_string(),
+            callme(3).with_context(|| "Inner").with_context(|| "Outer").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_message_chain() {
+        assert_eq!(MyError::Error1.message_chain(), vec!["Error 1"]);
+        let err = callme(1).with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        assert_eq!(err.message_chain(), vec!["Outer", "Inner", "Error 1"]);
+    }
+
+    #[test]
+    fn test_attach_context() {
+        let err = MyError::Error1.attach_context("ctx");
+        assert_eq!(err.message_chain(), vec!["ctx", "Error 1"]);
+        let err = err.attach_context(String::from("outer"));
+        assert_eq!(err.message_chain(), vec!["outer", "ctx", "Error 1"]);
+        assert!(err.is_error1());
+    }
+
+    struct Path<'a>(&'a str);
+
+    impl std::fmt::Display for Path<'_> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "path {}", self.0)
+        }
+    }
+
+    #[test]
+    fn test_display_context() {
+        let err = callme(1).with_context(|| 42u64).unwrap_err();
+        assert_eq!(err.outermost_context(), Some("42"));
+        let err = callme(1).with_context(|| String::from("owned")).unwrap_err();
+        assert_eq!(err.outermost_context(), Some("owned"));
+        let err = callme(1).with_context(|| Path("/etc/app.conf")).unwrap_err();
+        assert_eq!(err.outermost_context(), Some("path /etc/app.conf"));
+        let name = String::from("borrowed");
+        let err = callme(1).with_context(|| std::borrow::Cow::Borrowed(name.as_str())).unwrap_err();
+        assert_eq!(err.outermost_context(), Some("borrowed"));
+    }
+
+    // Generic over the declared trait signature
+    fn annotate<R, T>(res: R, ctx: &str) -> Result<T, MyError>
+    where
+        R: AddErrorContext<MyError, T>,
+    {
+        res.with_context(|| ctx.to_string())
+    }
+
+    #[test]
+    fn test_generic_over_trait() {
+        let (ctx,err) = annotate(callme(1), "Generic").unwrap_err().unwrap_context();
+        assert_eq!(ctx.as_deref(), Some("Generic"));
+        assert!(matches!(err, MyError::Error1));
+        assert!(annotate(callme(42), "Generic").is_ok());
+    }
+
+    #[derive(Debug)]
+    struct HandError(&'static str);
+
+    // Only `with_context()` and `into_target()` are written by hand, the rest are the provided methods
+    impl<T> AddErrorContext<MyError, T> for Result<T, HandError> {
+        fn with_context<S: std::fmt::Display>(self, f: impl FnOnce() -> S) -> Result<T, MyError> {
+            self.map_err(|_| {
+                let ctx = f().to_string();
+                // The provided methods don't make layers with empty contexts
+                assert!(!ctx.is_empty());
+                MyError::Error2.push_context(ctx)
+            })
+        }
+
+        fn into_target(self) -> Result<T, MyError> {
+            self.map_err(|_| MyError::Error2)
+        }
+    }
+
+    #[test]
+    fn test_provided_methods() {
+        let parse = |s: &str| s.parse::<i32>().map_err(|_| HandError("invalid digit"));
+        assert_eq!(parse("1").context("unused").unwrap(), 1);
+        assert_eq!(parse("x").context("parsing").unwrap_err().full_message(), "parsing: Error 2");
+        assert_eq!(parse("x").with_context_tap(|| "tapped", |e| assert!(e.has_context())).unwrap_err().full_message(), "tapped: Error 2");
+        assert_eq!(parse("x").with_context_args(|w| w(format_args!("row {}", 3))).unwrap_err().full_message(), "row 3: Error 2");
+        assert_eq!(parse("x").with_path_context("/etc/app.conf").unwrap_err().full_message(), "/etc/app.conf: Error 2");
+
+        // The error is converted without a context
+        let err = parse("x").with_context_if(false, || -> String { unreachable!() }).unwrap_err();
+        assert!(!err.has_context());
+        let err = parse("x").with_scoped_context().unwrap_err();
+        assert!(!err.has_context());
+        let err = parse("x").or_else_context(|| "ensured").unwrap_err();
+        assert_eq!(err.full_message(), "ensured: Error 2");
+
+        let err = parse("x").with_context_with(|e| format!("parsing ({})", e.0)).unwrap_err();
+        assert_eq!(err.full_message(), "parsing (invalid digit): Error 2");
+        let err = parse("x").map_to_context(|_| (MyError::Error3, "mapped".to_string())).unwrap_err();
+        assert_eq!(err.full_message(), "mapped: Error 3");
+
+        #[cfg(feature = "fields")]
+        {
+            let err = parse("x").with_fields(|| [("row", "3")]).unwrap_err();
+            assert_eq!(err.context_fields(), [("row".to_string(), "3".to_string())]);
+        }
+        #[cfg(feature = "severity")]
+        {
+            let err = parse("x").with_context_severity(Severity::Warning, || "skipped").unwrap_err();
+            assert_eq!(err.severity(), Some(Severity::Warning));
+        }
+    }
+
+    fn try_literal(n: i32) -> Result<i32,MyError> {
+        try_context!(callme(n), "Literal context");
+        Ok(n)
+    }
+
+    fn try_format(n: i32, evaluated: &std::cell::Cell<bool>) -> Result<i32,MyError> {
+        let name = || { evaluated.set(true); "value" };
+        try_context!(callme(n), "Loading {} {}", name(), n);
+        Ok(n)
+    }
+
+    #[test]
+    fn test_try_context() {
+        let (ctx,err) = try_literal(1).unwrap_err().unwrap_context();
+        assert_eq!(ctx.as_deref(), Some("Literal context"));
+        assert!(matches!(err, MyError::Error1));
+
+        let evaluated = std::cell::Cell::new(false);
+        let (ctx,_) = try_format(2, &evaluated).unwrap_err().unwrap_context();
+        assert_eq!(ctx.as_deref(), Some("Loading value 2"));
+        assert!(evaluated.get());
+
+        // Format arguments are not evaluated on success
+        let evaluated = std::cell::Cell::new(false);
+        assert_eq!(try_format(42, &evaluated).unwrap(), 42);
+        assert!(!evaluated.get());
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn test_assert_error_matches() {
+        assert_error_matches!(callme(1), MyError::Error1);
+        assert_error_matches!(callme(2).with_context(|| "Context"), MyError::Error2);
+        let res: Result<(),PeeledError> = Err(PeeledError::Overflow(5));
+        assert_error_matches!(res.with_context(|| "Context"), PeeledError::Overflow(n) if *n > 1);
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    #[should_panic(expected = "error `Error1` with context Some(\"Context\") doesn't match `MyError::Error2`")]
+    fn test_assert_error_matches_mismatch() {
+        assert_error_matches!(callme(1).with_context(|| "Context"), MyError::Error2);
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    #[should_panic(expected = "got Ok")]
+    fn test_assert_error_matches_ok() {
+        assert_error_matches!(callme(42), MyError::Error1);
+    }
+
+    #[test]
+    fn test_cloned_without_context() {
+        let res: Result<(),EqError> = Err(EqError::Overflow(7));
+        let err = res.with_context(|| "Inner").with_context(|| "Outer").unwrap_err();
+        let cloned = err.cloned_without_context();
+        assert_eq!(cloned, EqError::Overflow(7));
+        assert_eq!(cloned.outermost_context(), None);
+        // The original is untouched
+        assert_eq!(err.full_message(), "Outer: Inner: Overflow 7");
+    }
+
+    #[test]
+    #[cfg(feature = "fields")]
+    fn test_fields() {
+        let path = String::from("/etc/app.conf");
+        let err = callme(1)
+            .with_context(|| "Loading config")
+            .with_fields(|| [("path", path.clone()), ("attempt", "3".to_string())])
+            .unwrap_err();
+        assert_eq!(
+            err.context_fields(),
+            &[("path".to_string(), path), ("attempt".to_string(), "3".to_string())]
+        );
+        assert_eq!(err.to_string(), "Custom context messag: Loading config path=/etc/app.conf attempt=3");
+        assert!(MyError::Error1.context_fields().is_empty());
+
+        // The fields without the context string
+        let err = callme(2).with_fields(|| [("attempt", "1")]).unwrap_err();
+        assert_eq!(err.to_string(), "Custom context messag:  attempt=1");
+        assert_eq!(err.context_str(), "");
+        assert_eq!(err.context_fields(), &[("attempt".to_string(), "1".to_string())]);
+        // Contexts without the fields are displayed as usual
+        let err = callme(2).with_context(|| "Plain").unwrap_err();
+        assert_eq!(err.to_string(), "Custom context messag: Plain");
+    }
+
+    #[test]
+    #[cfg(feature = "termination")]
+    fn test_termination_message() {
+        let err = callme(1).with_context(|| "Crashing with value 1").unwrap_err();
+        assert_eq!(
+            err.termination_message(),
+            "Custom context messag: Crashing with value 1\n\nCaused by:\n    Error 1"
+        );
+        assert_eq!(MyError::Error2.termination_message(), "Error 2");
+    }
 
 }
\ No newline at end of file
@@ -1,80 +1,2393 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use quote::quote;
+use quote::{format_ident, quote};
 use syn::{
-    parse::{Parse, ParseStream}, parse_macro_input, ItemEnum, LitStr, Variant
+    parse::{Parse, ParseStream}, parse_macro_input, punctuated::Punctuated,
+    Attribute, Fields, Ident, ItemEnum, LitStr, Path, Token, Variant
 };
 
+#[derive(Default)]
 struct ContextAttr {
     message: Option<LitStr>,
+    // Generate a field-less companion enum with the kinds of variants
+    kind: bool,
+    // Generate a companion enum mirroring the variants and `into_parts()`
+    parts: bool,
+    // Generate a Debug impl showing the contexts in a readable way
+    pretty_debug: bool,
+    // Generate a conversion into `std::io::Error`
+    into_io: bool,
+    // Generate a conversion into `String` rendering the chain of causes
+    into_string: bool,
+    // Generate a report wrapper for returning from `main`
+    report: bool,
+    // Don't generate the `AddErrorContext` impl
+    no_ext_impl: bool,
+    // Variant used to store boxed dynamic errors
+    boxed_error: Option<Path>,
+    // Annotated enums converted into this one with their contexts preserved
+    bridges: Vec<Path>,
+    // Variant created from bare string messages
+    message_variant: Option<Path>,
+    // Function rendering the context variant instead of the message
+    display_with: Option<Path>,
+    // Separator of the contexts in the flattened message
+    separator: Option<LitStr>,
+    // Typed fields stored in the context variant
+    typed_fields: Vec<(Ident, syn::Type)>,
+    // Path to the runtime crate if it is renamed or re-exported
+    crate_path: Option<Path>,
+    // Generate a hidden variant storing foreign errors with the context
+    external: bool,
+    // Key of the localized message looked up at runtime
+    key: Option<LitStr>,
+    // Prefix the message of the context variant with its severity
+    severity_prefix: bool,
+    // Render the context variant with the localized message
+    localized: bool,
+    // Level of the tracing event emitted for every added context
+    trace_level: Option<Ident>,
+    // Generate `set_context_inspector()` called for every added context
+    inspector: bool,
+    // Generate the inherent `try_each_with_context()`
+    try_each: bool,
+    // Generate the inherent `context_scope()`
+    scopes: bool,
 }
 
 
 impl Parse for ContextAttr {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        let message: Option<LitStr> = if input.is_empty() {
-            None
-        } else {
+        let message: Option<LitStr> = if input.peek(LitStr) {
             Some(input.parse()?)
+        } else {
+            None
         };
-        Ok(ContextAttr { message })
+
+        let mut attr = ContextAttr { message, ..Default::default() };
+
+        // Parse comma-separated options after the message
+        while !input.is_empty() {
+            if attr.message.is_some() || input.peek(Token![,]) {
+                input.parse::<Token![,]>()?;
+                if input.is_empty() {
+                    break;
+                }
+            }
+            let option: Ident = input.parse()?;
+            match option.to_string().as_str() {
+                "kind" => attr.kind = true,
+                "localized" => attr.localized = true,
+                "severity_prefix" => attr.severity_prefix = true,
+                "key" => {
+                    input.parse::<Token![=]>()?;
+                    attr.key = Some(input.parse()?);
+                }
+                "external" => attr.external = true,
+                "parts" => attr.parts = true,
+                "pretty_debug" => attr.pretty_debug = true,
+                "into_io" => attr.into_io = true,
+                "into_string" => attr.into_string = true,
+                "report" => attr.report = true,
+                "no_ext_impl" => attr.no_ext_impl = true,
+                "boxed_error" => {
+                    input.parse::<Token![=]>()?;
+                    attr.boxed_error = Some(input.parse()?);
+                }
+                "separator" => {
+                    input.parse::<Token![=]>()?;
+                    attr.separator = Some(input.parse()?);
+                }
+                "display_with" => {
+                    input.parse::<Token![=]>()?;
+                    attr.display_with = Some(input.parse()?);
+                }
+                "trace_level" => {
+                    input.parse::<Token![=]>()?;
+                    let level: Ident = input.parse()?;
+                    if !["TRACE", "DEBUG", "INFO", "WARN", "ERROR"].contains(&level.to_string().as_str()) {
+                        return Err(syn::Error::new(level.span(), "expected one of TRACE, DEBUG, INFO, WARN or ERROR"));
+                    }
+                    attr.trace_level = Some(level);
+                }
+                "crate_path" => {
+                    input.parse::<Token![=]>()?;
+                    attr.crate_path = Some(Path::parse_mod_style(input)?);
+                }
+                "inspector" => attr.inspector = true,
+                "try_each" => attr.try_each = true,
+                "scopes" => attr.scopes = true,
+                "message_variant" => {
+                    input.parse::<Token![=]>()?;
+                    attr.message_variant = Some(input.parse()?);
+                }
+                "fields" => {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    while !content.is_empty() {
+                        let name: Ident = content.parse()?;
+                        content.parse::<Token![:]>()?;
+                        let ty: syn::Type = content.parse()?;
+                        if attr.typed_fields.iter().any(|(n, _)| *n == name) {
+                            return Err(syn::Error::new(name.span(), format!("duplicate context field `{}`", name)));
+                        }
+                        attr.typed_fields.push((name, ty));
+                        if !content.is_empty() {
+                            content.parse::<Token![,]>()?;
+                        }
+                    }
+                }
+                "bridge" => {
+                    input.parse::<Token![=]>()?;
+                    let lit: LitStr = input.parse()?;
+                    attr.bridges.push(lit.parse()?);
+                }
+                _ => return Err(syn::Error::new(
+                    option.span(),
+                    format!("unknown string_context option `{}`", option),
+                )),
+            }
+        }
+        Ok(attr)
+    }
+}
+
+// Checks that the template only references the context string as `{0}`
+// or the named fields of base variants. Returns the names of referenced fields.
+fn validate_template(template: &LitStr, field_names: &[String]) -> syn::Result<Vec<String>> {
+    let mut used_fields = Vec::new();
+    let value = template.value();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(syn::Error::new(
+                            template.span(),
+                            "unclosed `{` in the context message, use `{{` to escape it",
+                        )),
+                    }
+                }
+                let (arg, spec) = placeholder.split_once(':').unwrap_or((&placeholder, ""));
+                let arg = arg.trim();
+                // The context string is rendered with Display or Debug,
+                // while the fields are only known to implement Display
+                if let Err(msg) = validate_format_spec(spec, arg == "0") {
+                    return Err(syn::Error::new(
+                        template.span(),
+                        format!("unsupported format `{{{}}}` in the context message: {}", placeholder, msg),
+                    ));
+                }
+                if field_names.iter().any(|f| f == arg) {
+                    if !used_fields.iter().any(|f| f == arg) {
+                        used_fields.push(arg.to_string());
+                    }
+                } else if arg != "0" {
+                    return Err(syn::Error::new(
+                        template.span(),
+                        format!(
+                            "unsupported placeholder `{{{}}}` in the context message, only `{{0}}` referring to the context string \
+                            or names of the fields of base variants are allowed",
+                            placeholder
+                        ),
+                    ));
+                }
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+            }
+            '}' => return Err(syn::Error::new(
+                template.span(),
+                "unmatched `}` in the context message, use `}}` to escape it",
+            )),
+            _ => {}
+        }
+    }
+    Ok(used_fields)
+}
+
+// Renames the `{0}` placeholders of the validated template to `{__context}`,
+// since the context string is a named field of the context variant.
+// Returns the new template and whether the context string is used at all.
+fn rename_context_placeholder(template: &LitStr) -> (LitStr, bool) {
+    let value = template.value();
+    let mut renamed = String::new();
+    let mut used = false;
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        renamed.push(c);
+        match c {
+            '{' if chars.peek() == Some(&'{') => renamed.push(chars.next().unwrap()),
+            '{' => {
+                let placeholder: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                match placeholder.split_once(':').unwrap_or((&placeholder, "")) {
+                    (arg, spec) if arg.trim() == "0" => {
+                        used = true;
+                        renamed.push_str("__context");
+                        if placeholder.contains(':') {
+                            renamed.push(':');
+                            renamed.push_str(spec);
+                        }
+                    }
+                    _ => renamed.push_str(&placeholder),
+                }
+                renamed.push('}');
+            }
+            '}' => renamed.push(chars.next().unwrap()),
+            _ => {}
+        }
+    }
+    (LitStr::new(&renamed, template.span()), used)
+}
+
+// Checks the format spec of a placeholder: `[[fill]align][sign][#][0][width][.precision][type]`.
+// Width and precision taken from other arguments are not supported,
+// since there are no other arguments in the context variant.
+fn validate_format_spec(spec: &str, allow_debug: bool) -> Result<(), String> {
+    let chars: Vec<char> = spec.chars().collect();
+    let mut i = 0;
+    let is_align = |c: Option<&char>| matches!(c, Some('<' | '^' | '>'));
+    if is_align(chars.get(1)) {
+        i += 2;
+    } else if is_align(chars.first()) {
+        i += 1;
+    }
+    if matches!(chars.get(i), Some('+' | '-')) {
+        i += 1;
+    }
+    if chars.get(i) == Some(&'#') {
+        i += 1;
+    }
+    while chars.get(i).is_some_and(|c| c.is_ascii_digit()) {
+        i += 1;
+    }
+    if chars.get(i) == Some(&'.') {
+        i += 1;
+        let start = i;
+        while chars.get(i).is_some_and(|c| c.is_ascii_digit()) {
+            i += 1;
+        }
+        if i == start {
+            return Err("width and precision could only be integers".to_string());
+        }
+    }
+    let ty: String = chars[i..].iter().collect();
+    match ty.as_str() {
+        "" => Ok(()),
+        "?" | "x?" | "X?" if allow_debug => Ok(()),
+        "?" | "x?" | "X?" => Err("fields of base variants could only be formatted with `Display`".to_string()),
+        _ if ty.contains('$') || ty.contains('*') => {
+            Err("width and precision could only be integers".to_string())
+        }
+        _ if allow_debug => Err("the context string could only be formatted with `Display` or `Debug`".to_string()),
+        _ => Err("fields of base variants could only be formatted with `Display`".to_string()),
+    }
+}
+
+// Returns the type inside `Box<T>`, which is detected by the last segment of the path
+fn boxed_type(ty: &syn::Type) -> Option<&syn::Type> {
+    let syn::Type::Path(path) = ty else {
+        return None;
+    };
+    let last = path.path.segments.last()?;
+    if last.ident != "Box" {
+        return None;
+    }
+    match &last.arguments {
+        syn::PathArguments::AngleBracketed(args) => match args.args.first() {
+            Some(syn::GenericArgument::Type(inner)) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Converts CamelCase variant name to snake_case
+fn to_snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() {
+            let prev_lower = i > 0 && (chars[i - 1].is_lowercase() || chars[i - 1].is_ascii_digit());
+            let acronym_end = i > 0
+                && chars[i - 1].is_uppercase()
+                && chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if prev_lower || acronym_end {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+// Returns the traits derived by the `#[derive(...)]` attributes including the ones inside `#[cfg_attr(...)]`
+fn derived_traits(attrs: &[Attribute]) -> Vec<Path> {
+    let mut traits = Vec::new();
+    for attr in attrs {
+        collect_derives(&attr.meta, &mut traits);
+    }
+    traits
+}
+
+fn collect_derives(meta: &syn::Meta, traits: &mut Vec<Path>) {
+    let syn::Meta::List(list) = meta else {
+        return;
+    };
+    if list.path.is_ident("derive") {
+        if let Ok(paths) = list.parse_args_with(Punctuated::<Path, Token![,]>::parse_terminated) {
+            traits.extend(paths);
+        }
+    } else if list.path.is_ident("cfg_attr") {
+        // The condition is followed by the attributes
+        if let Ok(metas) = list.parse_args_with(Punctuated::<syn::Meta, Token![,]>::parse_terminated) {
+            for meta in metas.iter().skip(1) {
+                collect_derives(meta, traits);
+            }
+        }
+    }
+}
+
+// Removes the trait with given name from all `#[derive(...)]` attributes
+fn remove_derive(attrs: &mut Vec<Attribute>, name: &str) -> syn::Result<()> {
+    let mut result = Vec::with_capacity(attrs.len());
+    for attr in attrs.drain(..) {
+        if !attr.path().is_ident("derive") {
+            result.push(attr);
+            continue;
+        }
+        let traits = attr.parse_args_with(Punctuated::<Path, Token![,]>::parse_terminated)?;
+        let traits: Vec<Path> = traits
+            .into_iter()
+            .filter(|p| p.segments.last().is_none_or(|s| s.ident != name))
+            .collect();
+        if !traits.is_empty() {
+            result.push(syn::parse_quote! { #[derive(#(#traits),*)] });
+        }
+    }
+    *attrs = result;
+    Ok(())
+}
+
+// Generates a Debug impl, which shows base variants as derived Debug does
+// and prints the contexts after the base error
+fn pretty_debug_impl(enum_name: &Ident, variants: &Punctuated<Variant, Token![,]>, external: bool) -> proc_macro2::TokenStream {
+    let arms = variants.iter().map(|v| {
+        let name = &v.ident;
+        let name_str = name.to_string();
+        match &v.fields {
+            Fields::Unit => quote! {
+                Self::#name => f.write_str(#name_str),
+            },
+            Fields::Unnamed(fields) => {
+                let bindings: Vec<Ident> = (0..fields.unnamed.len())
+                    .map(|i| format_ident!("__field{}", i))
+                    .collect();
+                quote! {
+                    Self::#name(#(#bindings),*) => f.debug_tuple(#name_str)
+                        #(.field(#bindings))*
+                        .finish(),
+                }
+            }
+            Fields::Named(fields) => {
+                let bindings: Vec<&Ident> = fields.named.iter()
+                    .map(|f| f.ident.as_ref().unwrap())
+                    .collect();
+                let names = bindings.iter().map(|b| b.to_string());
+                quote! {
+                    Self::#name { #(#bindings),* } => f.debug_struct(#name_str)
+                        #(.field(#names, #bindings))*
+                        .finish(),
+                }
+            }
+        }
+    });
+
+    let external_arm = if external {
+        quote! { Self::__External { context, source } => f.debug_tuple("External").field(context).field(source).finish(), }
+    } else {
+        quote! {}
+    };
+
+    quote! {
+        impl std::fmt::Debug for #enum_name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    #(#arms)*
+                    #external_arm
+                    Self::__WithContext { .. } => {
+                        std::fmt::Debug::fmt(self.base_error(), f)?;
+                        let mut err = self;
+                        let mut first = true;
+                        while let Self::__WithContext { context: ctx, source: inner, .. } = err {
+                            if f.alternate() {
+                                write!(f, "\n  context: {:?}", ctx)?;
+                            } else if first {
+                                write!(f, " (context: {:?}", ctx)?;
+                            } else {
+                                write!(f, ", {:?}", ctx)?;
+                            }
+                            first = false;
+                            err = inner;
+                        }
+                        if f.alternate() {
+                            Ok(())
+                        } else {
+                            f.write_str(")")
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Checks if the variant is `#[error(transparent)]` wrapper of `io::Error`
+// and returns the pattern binding its field as `e`
+fn transparent_io_pattern(enum_name: &Ident, v: &Variant) -> Option<proc_macro2::TokenStream> {
+    let transparent = v.attrs.iter().any(|a| {
+        a.path().is_ident("error")
+            && a.parse_args::<Ident>().is_ok_and(|i| i == "transparent")
+    });
+    if !transparent || v.fields.len() != 1 {
+        return None;
+    }
+    let field = v.fields.iter().next().unwrap();
+    let syn::Type::Path(ty) = &field.ty else {
+        return None;
+    };
+    let segments: Vec<String> = ty.path.segments.iter().map(|s| s.ident.to_string()).collect();
+    if !segments.ends_with(&["io".to_string(), "Error".to_string()]) {
+        return None;
+    }
+    let name = &v.ident;
+    Some(match &field.ident {
+        Some(field_name) => quote! { #enum_name::#name { #field_name: e } },
+        None => quote! { #enum_name::#name(e) },
+    })
+}
+
+// Generates `AddBoxedErrorContext` impls storing the boxed errors in the designated variant
+fn boxed_error_impl(
+    enum_name: &Ident,
+    variants: &Punctuated<Variant, Token![,]>,
+    path: &Path,
+    krate: &proc_macro2::TokenStream,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &path.segments.last().unwrap().ident;
+    let variant = variants.iter().find(|v| v.ident == *name).ok_or_else(|| {
+        syn::Error::new_spanned(path, format!("variant `{}` designated as `boxed_error` is not found", name))
+    })?;
+    let field = match &variant.fields {
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => &fields.unnamed[0],
+        _ => return Err(syn::Error::new_spanned(
+            &variant.ident,
+            "the `boxed_error` variant must have a single unnamed field",
+        )),
+    };
+
+    // The message of the error is stored if the field is a String,
+    // otherwise the box itself is stored
+    let is_string = matches!(&field.ty, syn::Type::Path(ty) if ty.path.is_ident("String"));
+    let error_types = if is_string {
+        vec![
+            quote! { Box<dyn std::error::Error> },
+            quote! { Box<dyn std::error::Error + Send + Sync> },
+        ]
+    } else {
+        let ty = &field.ty;
+        vec![quote! { #ty }]
+    };
+    let conversion = if is_string {
+        quote! { e.to_string() }
+    } else {
+        quote! { e }
+    };
+
+    Ok(quote! {
+        #(
+            impl<T> #krate::AddBoxedErrorContext<#enum_name, T> for std::result::Result<T, #error_types> {
+                fn with_boxed_context<S: std::fmt::Display>(self, f: impl FnOnce() -> S) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| #enum_name::__new_context(#krate::__into_context(f()), #enum_name::#name(#conversion)))
+                }
+            }
+        )*
+    })
+}
+
+// Generates conversions from string messages into the designated variant
+fn message_variant_impl(
+    enum_name: &Ident,
+    variants: &Punctuated<Variant, Token![,]>,
+    path: &Path,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &path.segments.last().unwrap().ident;
+    let variant = variants.iter().find(|v| v.ident == *name).ok_or_else(|| {
+        syn::Error::new_spanned(path, format!("variant `{}` designated as `message_variant` is not found", name))
+    })?;
+    match &variant.fields {
+        Fields::Unnamed(fields)
+            if fields.unnamed.len() == 1
+                && matches!(&fields.unnamed[0].ty, syn::Type::Path(ty) if ty.path.is_ident("String")) => {}
+        _ => return Err(syn::Error::new_spanned(
+            &variant.ident,
+            "the `message_variant` variant must have a single unnamed `String` field",
+        )),
+    }
+
+    Ok(quote! {
+        impl From<String> for #enum_name {
+            fn from(msg: String) -> Self {
+                #enum_name::#name(msg)
+            }
+        }
+
+        impl From<&str> for #enum_name {
+            fn from(msg: &str) -> Self {
+                #enum_name::#name(msg.to_string())
+            }
+        }
+    })
+}
+
+// Finds the variant with a single field of the bridged enum type
+fn find_bridge_variant<'a>(
+    variants: &'a mut Punctuated<Variant, Token![,]>,
+    bridge: &Path,
+) -> syn::Result<&'a mut Variant> {
+    let bridge_name = &bridge.segments.last().unwrap().ident;
+    variants
+        .iter_mut()
+        .find(|v| {
+            v.fields.len() == 1 && matches!(
+                &v.fields.iter().next().unwrap().ty,
+                syn::Type::Path(ty) if ty.path.segments.last().is_some_and(|s| s.ident == *bridge_name)
+            )
+        })
+        .ok_or_else(|| syn::Error::new_spanned(
+            bridge,
+            format!("no variant with a single field of type `{}` to bridge into", bridge_name),
+        ))
+}
+
+// Generates the conversion from the bridged annotated enum, which moves
+// its contexts into the context layers of this enum
+fn bridge_impl(enum_name: &Ident, variant: &Variant, bridge: &Path) -> proc_macro2::TokenStream {
+    let name = &variant.ident;
+    let construct = match &variant.fields.iter().next().unwrap().ident {
+        Some(field) => quote! { #enum_name::#name { #field: err } },
+        None => quote! { #enum_name::#name(err) },
+    };
+    quote! {
+        impl From<#bridge> for #enum_name {
+            fn from(err: #bridge) -> Self {
+                // Peel the contexts off, outermost first
+                let mut contexts = Vec::new();
+                let mut err = err;
+                let err = loop {
+                    match err.unwrap_context() {
+                        (Some(ctx), inner) => {
+                            contexts.push(ctx);
+                            err = inner;
+                        }
+                        (None, base) => break base,
+                    }
+                };
+                contexts.into_iter().rev().fold(#construct, |inner, ctx| #enum_name::__new_context(ctx, inner))
+            }
+        }
     }
 }
 
+/// Adds the hidden context variant to the error enum deriving `thiserror::Error`.
+///
+/// `#[string_context("Custom context message: {0}")]` is placed above `#[derive(Error)]`,
+/// where `{0}` is the context string. The message could be followed by the options:
+/// - `kind` and `parts` generate the `MyErrorKind` and `MyErrorBase` enums for exhaustive matching
+/// - `separator = " / "` sets the separator of the contexts in `full_message()`
+/// - `pretty_debug` shows the base error followed by its contexts in `Debug`
+/// - `report` generates the `MyErrorReport` wrapper printing the chain of causes
+/// - `into_io` and `into_string` generate the conversions into `io::Error` and `String`
+/// - `boxed_error = MyError::Other` designates the variant storing boxed errors
+/// - `external` adds the hidden variant storing foreign errors with their context
+/// - `message_variant = Other` generates the conversions from string messages into the variant
+/// - `bridge = "InnerError"` keeps the contexts of the inner annotated enum after conversion
+/// - `display_with = render` renders the context variant with the function instead of the message
+/// - `key = "err.context", localized` looks up the message by the key at runtime
+/// - `fields(file: String, line: u32)` adds the typed fields to the context
+/// - `no_ext_impl` disables the generated `AddErrorContext` impl
+/// - `inspector`, `try_each` and `scopes` generate the inherent shortcuts for the hooks, iterators and scopes
+/// - `severity_prefix` and `trace_level = WARN` configure the `severity` and `tracing` features
+/// - `crate_path = ::my_reexport::tsc` gives the path to the renamed or re-exported crate
+///
+/// The variants could be marked with `#[exit_code(N)]`, `#[error_code("...")]`,
+/// `#[skip_is]` and `#[skip_as]`.
 #[proc_macro_attribute]
 pub fn string_context(attr: TokenStream, item: TokenStream) -> TokenStream {
     // Parse the custom message passed to the macro
     let context_attr = parse_macro_input!(attr as ContextAttr);
-    let custom_message = context_attr
-        .message
+    // The message is not used if the context variant is rendered by a custom function
+    if let (Some(message), Some(_)) = (&context_attr.message, &context_attr.display_with) {
+        if !message.value().is_empty() {
+            return syn::Error::new(
+                message.span(),
+                "the context message is not used with `display_with`, omit it or leave it empty",
+            )
+            .into_compile_error()
+            .into();
+        }
+    }
+    // The localized message is looked up by the key instead of the literal message
+    match (&context_attr.key, context_attr.localized) {
+        (Some(key), false) => return syn::Error::new(
+            key.span(),
+            "the message `key` requires the `localized` option",
+        )
+        .into_compile_error()
+        .into(),
+        (None, true) => return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "the `localized` option requires the message key like `key = \"err.context\"`",
+        )
+        .into_compile_error()
+        .into(),
+        (Some(key), true) if context_attr.message.is_some() || context_attr.display_with.is_some() => {
+            return syn::Error::new(
+                key.span(),
+                "the localized message can't be combined with the literal message or `display_with`",
+            )
+            .into_compile_error()
+            .into();
+        }
+        _ => {}
+    }
+    let message = context_attr.message.clone().filter(|_| context_attr.display_with.is_none());
+
+    // Without a message the context variant is displayed as the context string alone
+    let custom_message = message
         .unwrap_or_else(|| LitStr::new("{0}", proc_macro2::Span::call_site()));
+    if custom_message.value().is_empty() {
+        return syn::Error::new(
+            custom_message.span(),
+            "the context message is empty, omit it to use the default `{0}`",
+        )
+        .into_compile_error()
+        .into();
+    }
 
+    // Paths to the runtime items are absolute unless the crate is renamed
+    let krate = match &context_attr.crate_path {
+        Some(path) => quote! { #path },
+        None => quote! { ::thiserror_string_context },
+    };
 
     // Parse the input enum
     let input_enum = parse_macro_input!(item as ItemEnum);
     let enum_name = &input_enum.ident;
     let visibility = &input_enum.vis; // Get the visibility of the enum
 
+    // The context variant carries data, so the enum can't have explicit discriminants
+    if let Some((_, discriminant)) = input_enum.variants.iter().find_map(|v| v.discriminant.as_ref()) {
+        return syn::Error::new_spanned(
+            discriminant,
+            "string_context doesn't support enums with explicit discriminants: \
+            the added context variant carries data, which is incompatible with \
+            `as` casts and `#[repr]` requirements of such enums",
+        )
+        .into_compile_error()
+        .into();
+    }
+
+    // The context variant relies on `#[error]` handled by `thiserror::Error`,
+    // which must be derived after this attribute to see the added variant.
+    // The derive is either imported as `Error` or given by a path ending with `thiserror::Error`,
+    // which also covers re-exports of `thiserror`.
+    let derived = derived_traits(&input_enum.attrs);
+    let derives_error = derived.iter().any(|p| {
+        let segments: Vec<String> = p.segments.iter().map(|s| s.ident.to_string()).collect();
+        p.is_ident("Error") || segments.ends_with(&["thiserror".to_string(), "Error".to_string()])
+    });
+    if !derives_error {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "string_context requires `#[derive(thiserror::Error)]` on the enum, \
+            placed below the `#[string_context]` attribute",
+        )
+        .into_compile_error()
+        .into();
+    }
+
+    // Structured key-value fields stored in the context variant
+    let fields_enabled = cfg!(feature = "fields");
+    // The thread, where the context was added
+    let thread_enabled = cfg!(feature = "thread-name");
+    // The severity of the context
+    let severity_enabled = cfg!(feature = "severity");
+    if context_attr.severity_prefix && !severity_enabled {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "the `severity_prefix` option requires the `severity` feature",
+        )
+        .into_compile_error()
+        .into();
+    }
+    // The tracing event emitted when the context is added
+    let tracing_enabled = cfg!(feature = "tracing");
+    if context_attr.trace_level.is_some() && !tracing_enabled {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "the `trace_level` option requires the `tracing` feature",
+        )
+        .into_compile_error()
+        .into();
+    }
+    let trace_event = if tracing_enabled {
+        let level = context_attr.trace_level.clone().unwrap_or_else(|| format_ident!("DEBUG"));
+        quote! {
+            if let Self::__WithContext { context: ctx, source: inner, .. } = &err {
+                #krate::__tracing::event!(#krate::__tracing::Level::#level, context = %ctx, error = %inner, "error context attached");
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // The optional inspector called for every added context
+    let (inspect_call, inspector_impl) = if context_attr.inspector {
+        (
+            quote! {
+                if let Some(inspect) = Self::__context_inspector().get() {
+                    if let Self::__WithContext { context: ctx, .. } = &err {
+                        inspect(ctx, &err);
+                    }
+                }
+            },
+            quote! {
+                // The inspector called for every added context if it is installed
+                #[allow(clippy::type_complexity)]
+                fn __context_inspector() -> &'static std::sync::OnceLock<Box<dyn Fn(&str, &#enum_name) + Send + Sync>> {
+                    static INSPECTOR: std::sync::OnceLock<Box<dyn Fn(&str, &#enum_name) + Send + Sync>> = std::sync::OnceLock::new();
+                    &INSPECTOR
+                }
+
+                /// Installs the function called with the context and the wrapped error
+                /// every time a context is added to this enum. Only the first inspector
+                /// is installed, `false` is returned if there is one already.
+                #visibility fn set_context_inspector(f: impl Fn(&str, &#enum_name) + Send + Sync + 'static) -> bool {
+                    Self::__context_inspector().set(Box::new(f)).is_ok()
+                }
+            },
+        )
+    } else {
+        (quote! {}, quote! {})
+    };
+
+    // Named fields of the context variant after the context and the wrapped error
+    // with their types and the values of the new contexts
+    let mut extras: Vec<(Ident, proc_macro2::TokenStream, proc_macro2::TokenStream)> = Vec::new();
+    if fields_enabled {
+        extras.push((format_ident!("fields"), quote! { Vec<(String, String)> }, quote! { Vec::new() }));
+    }
+    if thread_enabled {
+        extras.push((format_ident!("thread"), quote! { #krate::ContextThread }, quote! { #krate::ContextThread::current() }));
+    }
+    if severity_enabled {
+        extras.push((format_ident!("severity"), quote! { #krate::Severity }, quote! { #krate::Severity::default() }));
+    }
+    // Typed fields declared by `fields(...)` follow the extra fields and are empty by default
+    let typed_fields = &context_attr.typed_fields;
+    for (name, ty) in typed_fields {
+        extras.push((format_ident!("field_{}", name), quote! { Option<#ty> }, quote! { None }));
+    }
+    let extra_names: Vec<_> = extras.iter().map(|(name, ..)| name).collect();
+    let extra_types: Vec<_> = extras.iter().map(|(_, ty, _)| ty).collect();
+    let extra_values: Vec<_> = extras.iter().map(|(.., value)| value).collect();
+    let extra_fields = quote! { #(, #extra_names: #extra_types)* };
+    let extra_init = quote! { #(, #extra_names: #extra_values)* };
+
+    // Names of the named fields of all base variants
+    let mut field_names: Vec<String> = Vec::new();
+    for v in input_enum.variants.iter() {
+        for name in v.fields.iter().filter_map(|f| f.ident.as_ref()).map(|i| i.to_string()) {
+            if !field_names.contains(&name) {
+                field_names.push(name);
+            }
+        }
+    }
+
+    // Typed fields of the context shadow the fields of base variants with the same name
+    for (name, _) in typed_fields {
+        let name = name.to_string();
+        if !field_names.contains(&name) {
+            field_names.push(name);
+        }
+    }
+
+    // Fields of base variants referenced in the template
+    let (used_typed_fields, used_fields): (Vec<String>, Vec<String>) = match validate_template(&custom_message, &field_names) {
+        Ok(used_fields) => used_fields.into_iter().partition(|f| typed_fields.iter().any(|(n, _)| n == f)),
+        Err(err) => return err.into_compile_error().into(),
+    };
+    // The typed fields are rendered from the context variant itself
+    let typed_field_args = used_typed_fields.iter().map(|f| {
+        let field = format_ident!("{}", f);
+        let stored = format_ident!("field_{}", f);
+        quote! { #field = #krate::DisplayOption(.#stored) }
+    });
+
+    // The fields are rendered by the helper methods, which peel the contexts
+    // and return the field if the base variant has it or an empty string otherwise
+    let field_args = used_fields.iter().map(|f| {
+        let field = format_ident!("{}", f);
+        let getter = format_ident!("__context_field_{}", f);
+        quote! { #field = #enum_name::#getter(.source) }
+    }).chain(typed_field_args).collect::<Vec<_>>();
+    let field_getters = used_fields.iter().map(|f| {
+        let field = format_ident!("{}", f);
+        let getter = format_ident!("__context_field_{}", f);
+        let variants_with_field = input_enum.variants.iter()
+            .filter(|v| v.fields.iter().any(|vf| vf.ident.as_ref().is_some_and(|i| i == f)))
+            .map(|v| &v.ident);
+        quote! {
+            #[doc(hidden)]
+            fn #getter(inner: &Self) -> &dyn std::fmt::Display {
+                match inner.base_error() {
+                    #(Self::#variants_with_field { #field, .. } => #field,)*
+                    _ => &"",
+                }
+            }
+        }
+    });
+
+    // The context string is passed to the message by name
+    let (custom_message, context_arg) = match rename_context_placeholder(&custom_message) {
+        (message, true) => (message, quote! { , __context = .context }),
+        (message, false) => (message, quote! {}),
+    };
+
+    // With the `fields` feature the fields of the context are appended to the message
+    let (custom_message, fields_arg) = if fields_enabled {
+        (
+            LitStr::new(&format!("{}{{__fields}}", custom_message.value()), custom_message.span()),
+            quote! { , __fields = #krate::DisplayFields(.fields) },
+        )
+    } else {
+        (custom_message, quote! {})
+    };
+    let display_with_message = if fields_enabled { "{}{__fields}" } else { "{}" };
+
+    // The severity prefix like `[warning] ` is put before the message
+    let (custom_message, display_with_message, fields_arg) = if context_attr.severity_prefix {
+        (
+            LitStr::new(&format!("{{__severity}}{}", custom_message.value()), custom_message.span()),
+            format!("{{__severity}}{}", display_with_message),
+            quote! { #fields_arg, __severity = #krate::SeverityPrefix(.severity) },
+        )
+    } else {
+        (custom_message, display_with_message.to_string(), fields_arg)
+    };
+
+    // The custom function renders the context variant through the helper adaptor
+    let (error_attr, display_with) = match &context_attr.display_with {
+        None if context_attr.localized => {
+            let key = &context_attr.key;
+            (
+                quote! { #[error(#display_with_message, #krate::LocalizedContext(#key, .context) #fields_arg)] },
+                quote! {},
+            )
+        }
+        Some(render) => (
+            quote! { #[error(#display_with_message, #enum_name::__display_with(.context, .source) #fields_arg)] },
+            quote! {
+                #[doc(hidden)]
+                fn __display_with<'a>(ctx: &'a str, inner: &'a Self) -> impl std::fmt::Display + 'a {
+                    struct DisplayWith<'a>(&'a str, &'a #enum_name);
+
+                    impl std::fmt::Display for DisplayWith<'_> {
+                        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                            #render(self.0, self.1, f)
+                        }
+                    }
+
+                    DisplayWith(ctx, inner)
+                }
+            },
+        ),
+        None => (quote! { #[error(#custom_message #context_arg #(, #field_args)* #fields_arg)] }, quote! {}),
+    };
+
     // Create the new variant with the custom message
-    let new_variant: Variant = syn::parse_quote! {
-        #[error(#custom_message)]
-        __WithContext(String, #[source] Box<#enum_name>)
+    let mut new_variant: Variant = syn::parse_quote! {
+        #error_attr
+        __WithContext { context: std::borrow::Cow<'static, str>, #[source] source: Box<#enum_name> #extra_fields }
     };
 
-    // Append the new variant to the existing variants
-    let mut variants = input_enum.variants;
-    variants.push(new_variant);
+    // Serde derives requested for the enum
+    let derives = |name: &str| derived.iter().any(|p| p.segments.last().is_some_and(|s| s.ident == name));
+    let derives_ser = cfg!(feature = "serde") && derives("Serialize");
+    let derives_de = cfg!(feature = "serde") && derives("Deserialize");
+    let derives_serde = derives_ser || derives_de;
+
+    // The context variant is (de)serialized as `{"context": "...", "error": {...}}` in all
+    // representations of the enum, the extra fields are not serialized and get their defaults
+    // when deserialized. Being a struct variant it is also accepted in internally tagged enums.
+    if derives_serde {
+        new_variant.attrs.push(syn::parse_quote! { #[serde(rename = "WithContext")] });
+    }
+    if derives_ser {
+        let path = LitStr::new(&format!("{}::__serialize_context", enum_name), enum_name.span());
+        new_variant.attrs.push(syn::parse_quote! { #[serde(serialize_with = #path)] });
+    }
+    if derives_de {
+        let path = LitStr::new(&format!("{}::__deserialize_context", enum_name), enum_name.span());
+        new_variant.attrs.push(syn::parse_quote! { #[serde(deserialize_with = #path)] });
+    }
+    // The outermost context is shown as the help of the miette diagnostic,
+    // the diagnostics of the base variants are unchanged
+    if cfg!(feature = "miette") && derives("Diagnostic") {
+        if let Some(ctx) = new_variant.fields.iter_mut().next() {
+            ctx.attrs.push(syn::parse_quote! { #[help] });
+        }
+    }
 
-    let attrs = input_enum.attrs;
+    let serde_impl = if derives_serde {
+        let serde = quote! { #krate::__serde };
+        let serde_crate = LitStr::new(&serde.to_string(), proc_macro2::Span::call_site());
+        let ser = derives_ser.then(|| quote! {
+            #[doc(hidden)]
+            #[allow(clippy::borrowed_box, clippy::ptr_arg)]
+            fn __serialize_context<S: #serde::Serializer>(
+                ctx: &std::borrow::Cow<'static, str>,
+                inner: &Box<#enum_name>,
+                #(_: &#extra_types,)*
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                use #serde::ser::SerializeStruct;
+                let mut s = serializer.serialize_struct("WithContext", 2)?;
+                s.serialize_field("context", ctx)?;
+                s.serialize_field("error", &**inner)?;
+                s.end()
+            }
+        });
+        let de = derives_de.then(|| quote! {
+            #[doc(hidden)]
+            #[allow(clippy::type_complexity)]
+            fn __deserialize_context<'de, D: #serde::Deserializer<'de>>(
+                deserializer: D,
+            ) -> std::result::Result<(std::borrow::Cow<'static, str>, Box<#enum_name> #(, #extra_types)*), D::Error> {
+                #[derive(#serde::Deserialize)]
+                #[serde(crate = #serde_crate)]
+                struct WithContext<E> {
+                    context: String,
+                    error: Box<E>,
+                }
 
-    // Generate the modified enum with the new variant
-    let output = quote! {
-        //#[derive(Error, Debug)]
-        #(#attrs)*
-        #visibility enum #enum_name {
-            #variants
+                let WithContext { context, error } =
+                    <WithContext<#enum_name> as #serde::Deserialize>::deserialize(deserializer)?;
+                Ok((context.into(), error #(, #extra_values)*))
+            }
+        });
+        quote! { #ser #de }
+    } else {
+        quote! {}
+    };
+
+    // The variant storing foreign errors, which don't convert into the enum
+    let external_variant: Option<Variant> = context_attr.external.then(|| {
+        let mut v: Variant = syn::parse_quote! {
+            #[error("{context}")]
+            __External { context: String, #[source] source: Box<dyn std::error::Error + Send + Sync + 'static> }
+        };
+        // Foreign errors can't be (de)serialized, so serializing them fails
+        if cfg!(feature = "serde") && derives_serde {
+            v.attrs.push(syn::parse_quote! { #[serde(skip)] });
         }
+        v
+    });
 
-        impl #enum_name {
-            pub fn unwrap_context(self) -> (Option<String>,Self) {
-                match self {
-                    Self::__WithContext(ctx,err) => (Some(ctx),*err),
-                    _ => (None,self),
+    // Names of the user-defined variants
+    let variant_names: Vec<&Ident> = input_enum.variants.iter().map(|v| &v.ident).collect();
+
+    // Foreign errors stored with `external` are also base errors
+    let external = context_attr.external;
+    let (external_name_arm, external_exit_code_arm, external_code_arm, external_message) = if external {
+        (
+            quote! { Self::__External { .. } => "External", },
+            quote! { Self::__External { .. } => 1, },
+            quote! { Self::__External { .. } => None, },
+            // The foreign error is the source, so it is shown after its context
+            quote! {
+                if let Self::__External { source, .. } = err {
+                    let _ = write!(msg, "{}{}", Self::CONTEXT_SEPARATOR, source);
+                }
+            },
+        )
+    } else {
+        (quote! {}, quote! {}, quote! {}, quote! {})
+    };
+    let external_impl = if external {
+        quote! {
+            impl<T, X> #krate::AddExternalContext<#enum_name, T> for std::result::Result<T, X>
+            where
+                X: std::error::Error + Send + Sync + 'static,
+            {
+                fn with_external_context<S: std::fmt::Display>(self, f: impl FnOnce() -> S) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| #enum_name::__External { context: #krate::__into_context(f()), source: Box::new(e) })
+                }
+            }
+
+            impl #enum_name {
+                /// Returns the context and the foreign error of the base error
+                /// if it was added with `with_external_context()`
+                #visibility fn external_error(&self) -> Option<(&str, &(dyn std::error::Error + Send + Sync + 'static))> {
+                    match self.base_error() {
+                        Self::__External { context, source } => Some((context, source.as_ref())),
+                        _ => None,
+                    }
                 }
             }
         }
+    } else {
+        quote! {}
+    };
 
-        impl<E,T,S> AddErrorContext<#enum_name, T,S> for std::result::Result<T, E>
-        where
-            E: Into<#enum_name>,
-            S: Into<String>,
-        {
-            fn with_context(self, f: impl FnOnce() -> S) -> std::result::Result<T, #enum_name> {
-                self.map_err(|e| #enum_name::__WithContext(f().into(), Box::new(e.into())))
+    // The serializable report of the error with its contexts
+    let report_methods = if context_attr.report && cfg!(feature = "serde") {
+        quote! {
+            /// Collects the message of the base error, the contexts starting from the outermost
+            /// one and the name of the base variant into an owned report
+            #visibility fn to_report(&self) -> #krate::ErrorReport {
+                let mut contexts = Vec::new();
+                let mut err = self;
+                while let Self::__WithContext { context: ctx, source: inner, .. } = err {
+                    contexts.push(ctx.to_string());
+                    err = inner;
+                }
+                #krate::ErrorReport {
+                    message: err.to_string(),
+                    contexts,
+                    kind: match err {
+                        #(Self::#variant_names { .. } => stringify!(#variant_names),)*
+                        #external_name_arm
+                        Self::__WithContext { .. } => unreachable!(),
+                    }
+                    .to_string(),
+                }
+            }
+
+            /// Renders the report returned by `to_report()` as JSON
+            #visibility fn to_json(&self) -> String {
+                self.to_report().to_json()
             }
         }
+    } else {
+        quote! {}
     };
 
-    output.into()
-}
\ No newline at end of file
+    // Optional loop over the items adding the index of the failing one
+    let try_each = if context_attr.try_each {
+        quote! {
+            /// Applies `f` to every item until the first error, which gets the context `item N`
+            /// with the index of the failing item
+            #visibility fn try_each_with_context<I, E>(
+                iter: I,
+                mut f: impl FnMut(I::Item) -> std::result::Result<(), E>,
+            ) -> std::result::Result<(), Self>
+            where
+                I: IntoIterator,
+                E: Into<Self>,
+            {
+                for (i, item) in iter.into_iter().enumerate() {
+                    f(item).map_err(|e| Self::__new_context(format!("item {i}"), e.into()))?;
+                }
+                Ok(())
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Optional shortcut for declaring the scoped contexts
+    let scopes = if context_attr.scopes {
+        quote! {
+            /// Declares the context for the current scope, see `thiserror_string_context::context_scope()`
+            #visibility fn context_scope(label: impl Into<String>) -> #krate::ContextScope {
+                #krate::context_scope(label)
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Optional field-less companion enum for exhaustive matching
+    let kind = if context_attr.kind {
+        let kind_name = format_ident!("{}Kind", enum_name);
+        let (external_kind_variant, external_kind_arm) = if external {
+            (
+                quote! {
+                    /// Foreign error added with `with_external_context()`
+                    External,
+                },
+                quote! { Self::__External { .. } => #kind_name::External, },
+            )
+        } else {
+            (quote! {}, quote! {})
+        };
+        let kind_doc = format!("Kinds of [`{}`] variants ignoring the context", enum_name);
+        quote! {
+            #[doc = #kind_doc]
+            #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+            #visibility enum #kind_name {
+                #(#variant_names,)*
+                #external_kind_variant
+            }
+
+            impl #enum_name {
+                /// Returns the kind of the base error ignoring the context
+                #visibility fn kind(&self) -> #kind_name {
+                    match self.base_error() {
+                        #(Self::#variant_names { .. } => #kind_name::#variant_names,)*
+                        #external_kind_arm
+                        Self::__WithContext { .. } => unreachable!(),
+                    }
+                }
+
+                /// Returns the name of the base variant ignoring the context
+                #visibility fn kind_name(&self) -> &'static str {
+                    match self.base_error() {
+                        #(Self::#variant_names { .. } => stringify!(#variant_names),)*
+                        #external_name_arm
+                        Self::__WithContext { .. } => unreachable!(),
+                    }
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Optional companion enum with the same variants as the user enum, but without the context
+    let parts = if context_attr.parts {
+        let base_name = format_ident!("{}Base", enum_name);
+        let base_doc = format!("Variants of [`{}`] without the context returned by `into_parts()`", enum_name);
+        let mut base_variants = input_enum.variants.clone();
+        if !base_variants.empty_or_trailing() {
+            base_variants.push_punct(Default::default());
+        }
+        for v in base_variants.iter_mut() {
+            v.attrs.retain(|a| a.path().is_ident("doc"));
+            for f in v.fields.iter_mut() {
+                f.attrs.retain(|a| a.path().is_ident("doc"));
+            }
+        }
+        let arms = input_enum.variants.iter().map(|v| {
+            let variant_name = &v.ident;
+            match &v.fields {
+                Fields::Unit => quote! { Self::#variant_name => #base_name::#variant_name },
+                Fields::Unnamed(fields) => {
+                    let bindings: Vec<_> = (0..fields.unnamed.len()).map(|i| format_ident!("__{}", i)).collect();
+                    quote! { Self::#variant_name(#(#bindings),*) => #base_name::#variant_name(#(#bindings),*) }
+                }
+                Fields::Named(fields) => {
+                    let names: Vec<_> = fields.named.iter().map(|f| &f.ident).collect();
+                    quote! { Self::#variant_name { #(#names),* } => #base_name::#variant_name { #(#names),* } }
+                }
+            }
+        }).chain(external.then(|| quote! { Self::__External { context, source } => #base_name::External(context, source) }));
+        let external_base_variant = if external {
+            quote! {
+                /// Foreign error added with `with_external_context()`
+                External(String, Box<dyn std::error::Error + Send + Sync + 'static>),
+            }
+        } else {
+            quote! {}
+        };
+        quote! {
+            #[doc = #base_doc]
+            #[derive(Debug)]
+            #visibility enum #base_name {
+                #base_variants
+                #external_base_variant
+            }
+
+            impl #enum_name {
+                /// Splits the error into the contexts joined by the separator and the base error,
+                /// which could be matched exhaustively without a wildcard arm
+                #[must_use]
+                #visibility fn into_parts(self) -> (Option<String>, #base_name) {
+                    let mut contexts: Option<String> = None;
+                    let mut err = self;
+                    while let Self::__WithContext { context: ctx, source: inner, .. } = err {
+                        match contexts.as_mut() {
+                            Some(contexts) => {
+                                contexts.push_str(Self::CONTEXT_SEPARATOR);
+                                contexts.push_str(&ctx);
+                            }
+                            None => contexts = Some(ctx.into_owned()),
+                        }
+                        err = *inner;
+                    }
+                    let base = match err {
+                        #(#arms,)*
+                        Self::__WithContext { .. } => unreachable!(),
+                    };
+                    (contexts, base)
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Accessors of the typed context fields and the extension trait adding them
+    let typed_fields_impl = if typed_fields.is_empty() {
+        quote! {}
+    } else {
+        let stored: Vec<_> = typed_fields.iter().map(|(n, _)| format_ident!("field_{}", n)).collect();
+        let bindings: Vec<_> = typed_fields.iter().map(|(n, _)| format_ident!("__{}", n)).collect();
+        let names: Vec<_> = typed_fields.iter().map(|(n, _)| n).collect();
+        let types: Vec<_> = typed_fields.iter().map(|(_, t)| t).collect();
+        let accessors = typed_fields.iter().zip(&stored).map(|((name, ty), stored)| {
+            let method = format_ident!("context_field_{}", name);
+            let doc = format!("Returns the context field `{}` of the outermost context, which has it", name);
+            quote! {
+                #[doc = #doc]
+                #visibility fn #method(&self) -> Option<&#ty> {
+                    let mut err = self;
+                    while let Self::__WithContext { source: inner, #stored: value, .. } = err {
+                        if let Some(value) = value {
+                            return Some(value);
+                        }
+                        err = inner;
+                    }
+                    None
+                }
+            }
+        });
+        let trait_name = format_ident!("{}ContextFields", enum_name);
+        let trait_doc = format!("Adds the context with the typed fields of [`{}`]", enum_name);
+        let ext_impl = if context_attr.no_ext_impl {
+            quote! {}
+        } else {
+            quote! {
+                impl<T, E> #trait_name<T> for std::result::Result<T, E> where E: Into<#enum_name> {
+                    fn with_context_fields<S: std::fmt::Display>(
+                        self,
+                        f: impl FnOnce() -> S,
+                        #(#names: #types),*
+                    ) -> std::result::Result<T, #enum_name> {
+                        self.map_err(|e| {
+                            let mut err = #enum_name::__new_context(#krate::__into_context(f()), e.into());
+                            if let #enum_name::__WithContext { #(#stored: #bindings,)* .. } = &mut err {
+                                #(*#bindings = Some(#names);)*
+                            }
+                            err
+                        })
+                    }
+                }
+            }
+        };
+        quote! {
+            impl #enum_name {
+                #(#accessors)*
+            }
+
+            #[doc = #trait_doc]
+            #visibility trait #trait_name<T> {
+                /// Adds the context returned by the closure together with the typed fields
+                fn with_context_fields<S: std::fmt::Display>(
+                    self,
+                    f: impl FnOnce() -> S,
+                    #(#names: #types),*
+                ) -> std::result::Result<T, #enum_name>;
+            }
+
+            #ext_impl
+        }
+    };
+
+    // is_<variant>() predicates, which could be disabled by `#[skip_is]` on the variant
+    let predicates = input_enum.variants.iter()
+        .filter(|v| !v.attrs.iter().any(|a| a.path().is_ident("skip_is")))
+        .map(|v| {
+            let variant_name = &v.ident;
+            let method = format_ident!("is_{}", to_snake_case(&variant_name.to_string()));
+            let doc = format!("Checks if the base error is `{}` ignoring the context", variant_name);
+            quote! {
+                #[doc = #doc]
+                #visibility fn #method(&self) -> bool {
+                    matches!(self.base_error(), Self::#variant_name { .. })
+                }
+            }
+        });
+
+    // as_<variant>() accessors of the data of base variants, which could be disabled by `#[skip_as]`
+    let accessors = input_enum.variants.iter()
+        .filter(|v| !v.fields.is_empty() && !v.attrs.iter().any(|a| a.path().is_ident("skip_as")))
+        .map(|v| {
+            let variant_name = &v.ident;
+            let method = format_ident!("as_{}", to_snake_case(&variant_name.to_string()));
+            let doc = format!("Returns the fields of the base error if it is `{}` ignoring the context", variant_name);
+            let types: Vec<_> = v.fields.iter().map(|f| &f.ty).collect();
+            let bindings: Vec<_> = v.fields.iter().enumerate()
+                .map(|(i, f)| f.ident.clone().unwrap_or_else(|| format_ident!("__{}", i)))
+                .collect();
+            let pattern = match &v.fields {
+                Fields::Named(_) => quote! { Self::#variant_name { #(#bindings),* } },
+                _ => quote! { Self::#variant_name(#(#bindings),*) },
+            };
+            let (ty, value) = if types.len() == 1 {
+                (quote! { &#(#types)* }, quote! { #(#bindings)* })
+            } else {
+                (quote! { (#(&#types),*) }, quote! { (#(#bindings),*) })
+            };
+            quote! {
+                #[doc = #doc]
+                #visibility fn #method(&self) -> Option<#ty> {
+                    match self.base_error() {
+                        #pattern => Some(#value),
+                        _ => None,
+                    }
+                }
+            }
+        });
+
+    // Source fields of base variants, which could be other annotated enums
+    let source_arms = input_enum.variants.iter().flat_map(|v| {
+        let variant_name = &v.ident;
+        let krate = &krate;
+        v.fields.iter().enumerate().filter(|(_, f)| {
+            f.attrs.iter().any(|a| a.path().is_ident("from") || a.path().is_ident("source"))
+                || f.ident.as_ref().is_some_and(|i| i == "source")
+        }).map(move |(i, f)| {
+            let pattern = match &f.ident {
+                Some(name) => quote! { Self::#variant_name { #name: field, .. } },
+                None => {
+                    let skip = (0..i).map(|_| quote! { _, });
+                    quote! { Self::#variant_name(#(#skip)* field, ..) }
+                }
+            };
+            quote! { #pattern => contexts.extend((&#krate::NestedContexts(field)).nested_contexts()), }
+        })
+    }).collect::<Vec<_>>();
+
+    // Exit codes of variants set by `#[exit_code(N)]`, the default is 1
+    let has_exit_codes = input_enum.variants.iter().any(|v| v.attrs.iter().any(|a| a.path().is_ident("exit_code")));
+    let mut exit_codes = Vec::new();
+    for v in input_enum.variants.iter() {
+        let code = match v.attrs.iter().find(|a| a.path().is_ident("exit_code")) {
+            Some(attr) => match attr.parse_args::<syn::LitInt>().and_then(|lit| lit.base10_parse::<u8>()) {
+                Ok(code) => code,
+                Err(err) => return err.into_compile_error().into(),
+            },
+            None => 1,
+        };
+        exit_codes.push(code);
+    }
+
+    // Stable error codes of variants set by `#[error_code("...")]`
+    let mut error_codes: Vec<Option<LitStr>> = Vec::new();
+    for v in input_enum.variants.iter() {
+        let code = match v.attrs.iter().find(|a| a.path().is_ident("error_code")) {
+            Some(attr) => match attr.parse_args::<LitStr>() {
+                Ok(code) => Some(code),
+                Err(err) => return err.into_compile_error().into(),
+            },
+            None => None,
+        };
+        if let Some(code) = &code {
+            if error_codes.iter().flatten().any(|c| c.value() == code.value()) {
+                return syn::Error::new(code.span(), format!("duplicate error code `{}`", code.value()))
+                    .into_compile_error()
+                    .into();
+            }
+        }
+        error_codes.push(code);
+    }
+    // `code()` is only generated if some variant has its own error code
+    let code_method = if error_codes.iter().any(Option::is_some) {
+        let error_codes = error_codes.iter().map(|c| match c {
+            Some(code) => quote! { Some(#code) },
+            None => quote! { None },
+        });
+        quote! {
+            /// Returns the error code of the base variant set by `#[error_code("...")]`
+            /// ignoring the context
+            #visibility fn code(&self) -> Option<&'static str> {
+                match self.base_error() {
+                    #(Self::#variant_names { .. } => #error_codes,)*
+                    #external_code_arm
+                    Self::__WithContext { .. } => unreachable!(),
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // `exit_code()` is only generated if some variant has its own exit code
+    let exit_code_method = if has_exit_codes {
+        quote! {
+            /// Returns the process exit code of the base variant set by `#[exit_code(N)]`
+            /// ignoring the context. The default is 1.
+            #visibility fn exit_code(&self) -> std::process::ExitCode {
+                let code: u8 = match self.base_error() {
+                    #(Self::#variant_names { .. } => #exit_codes,)*
+                    #external_exit_code_arm
+                    Self::__WithContext { .. } => unreachable!(),
+                };
+                std::process::ExitCode::from(code)
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let termination_code = if has_exit_codes {
+        quote! { self.exit_code() }
+    } else {
+        quote! { std::process::ExitCode::FAILURE }
+    };
+
+    // Append the new variant to the existing variants
+    let mut variants = input_enum.variants.clone();
+    // Remove our own helper attributes
+    for v in variants.iter_mut() {
+        v.attrs.retain(|a| {
+            !["skip_is", "skip_as", "exit_code", "error_code"].iter().any(|name| a.path().is_ident(name))
+        });
+    }
+
+    // Bridged variants get the conversion generated by us instead of `#[from]`
+    let mut bridges = Vec::new();
+    for bridge in &context_attr.bridges {
+        let variant = match find_bridge_variant(&mut variants, bridge) {
+            Ok(v) => v,
+            Err(err) => return err.into_compile_error().into(),
+        };
+        for field in variant.fields.iter_mut() {
+            for a in field.attrs.iter_mut() {
+                if a.path().is_ident("from") {
+                    *a = syn::parse_quote!(#[source]);
+                }
+            }
+        }
+        bridges.push(bridge_impl(enum_name, variant, bridge));
+    }
+    variants.push(new_variant);
+    variants.extend(external_variant);
+
+    let mut attrs = input_enum.attrs;
+
+    // Custom Debug impl replaces the derived one
+    let pretty_debug = if context_attr.pretty_debug {
+        if let Err(err) = remove_derive(&mut attrs, "Debug") {
+            return err.into_compile_error().into();
+        }
+        pretty_debug_impl(enum_name, &input_enum.variants, context_attr.external)
+    } else {
+        quote! {}
+    };
+
+    // Optional Termination support for use in `main`
+    let termination = if cfg!(feature = "termination") {
+        quote! {
+            impl #enum_name {
+                /// Formats the error with the chain of its causes
+                #visibility fn termination_message(&self) -> String {
+                    self.display_chain().to_string()
+                }
+            }
+
+            impl std::process::Termination for #enum_name {
+                fn report(self) -> std::process::ExitCode {
+                    eprintln!("Error: {}", self.termination_message());
+                    #termination_code
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let fields_methods = if fields_enabled {
+        quote! {
+            /// Returns the key-value fields of the outermost context
+            #visibility fn context_fields(&self) -> &[(String, String)] {
+                match self {
+                    Self::__WithContext { fields, .. } => fields,
+                    _ => &[],
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Access to the extra data of the outermost context for the default trait methods
+    let layers_fields = if fields_enabled {
+        quote! {
+            fn extend_context_fields(&mut self, new_fields: Vec<(String, String)>) {
+                if let Self::__WithContext { fields, .. } = self {
+                    fields.extend(new_fields);
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let layers_severity = if severity_enabled {
+        quote! {
+            fn set_context_severity(&mut self, new_severity: #krate::Severity) {
+                if let Self::__WithContext { severity, .. } = self {
+                    *severity = new_severity;
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Conversions into and from anyhow errors mapping the contexts onto anyhow contexts
+    let anyhow_methods = if cfg!(feature = "anyhow") {
+        quote! {
+            /// Converts the error into `anyhow::Error`, where each context becomes
+            /// an anyhow context around the base error
+            #visibility fn to_anyhow(self) -> #krate::__anyhow::Error {
+                let mut contexts = Vec::new();
+                let mut err = self;
+                while let Self::__WithContext { context: ctx, source: inner, .. } = err {
+                    contexts.push(ctx);
+                    err = *inner;
+                }
+                contexts.into_iter().rev().fold(#krate::__anyhow::Error::new(err), |err, ctx| err.context(ctx))
+            }
+
+            /// Extracts the error from `anyhow::Error` if it contains this enum.
+            /// The anyhow contexts around it become the contexts of the returned error,
+            /// otherwise the original error is returned back.
+            #visibility fn from_anyhow(err: #krate::__anyhow::Error) -> std::result::Result<Self, #krate::__anyhow::Error> {
+                if err.downcast_ref::<Self>().is_none() {
+                    return Err(err);
+                }
+                let contexts: Vec<String> = err.chain()
+                    .take_while(|e| !e.is::<Self>())
+                    .map(|e| e.to_string())
+                    .collect();
+                let base = err.downcast::<Self>()?;
+                Ok(contexts.into_iter().rev().fold(base, |inner, ctx| Self::__new_context(ctx, inner)))
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let severity_methods = if severity_enabled {
+        quote! {
+            /// Returns the severity of the outermost context or `None` if there is no context
+            #visibility fn severity(&self) -> Option<#krate::Severity> {
+                match self {
+                    Self::__WithContext { severity, .. } => Some(*severity),
+                    _ => None,
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let thread_methods = if thread_enabled {
+        quote! {
+            /// Returns the name of the thread, where the outermost context was added,
+            /// or `None` if there is no context or the thread is unnamed
+            #visibility fn context_thread(&self) -> Option<&str> {
+                match self {
+                    Self::__WithContext { thread, .. } => thread.name(),
+                    _ => None,
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Optional conversion into io::Error
+    let into_io = if context_attr.into_io {
+        // Variants transparently wrapping io errors
+        let io_patterns: Vec<_> = input_enum.variants.iter()
+            .filter_map(|v| transparent_io_pattern(enum_name, v))
+            .collect();
+        quote! {
+            impl From<#enum_name> for std::io::Error {
+                fn from(err: #enum_name) -> Self {
+                    match err {
+                        // Io error without the context is returned as is
+                        #(#io_patterns => e,)*
+                        err => {
+                            // Keep the kind of the wrapped io error
+                            let kind = match err.base_error() {
+                                #(#io_patterns => e.kind(),)*
+                                _ => std::io::ErrorKind::Other,
+                            };
+                            std::io::Error::new(kind, err.full_message())
+                        }
+                    }
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Optional conversion into String for the APIs accepting only strings
+    let into_string = if context_attr.into_string {
+        quote! {
+            impl From<#enum_name> for String {
+                fn from(err: #enum_name) -> Self {
+                    err.context_chain_display()
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Optional report wrapper, which shows the chain of causes in its Debug
+    let report = if context_attr.report {
+        let report_name = format_ident!("{}Report", enum_name);
+        let report_doc = format!(
+            "Wrapper of [`{}`] for returning from `main`, which `Debug` shows the chain of causes",
+            enum_name
+        );
+        quote! {
+            #[doc = #report_doc]
+            #visibility struct #report_name(#visibility #enum_name);
+
+            impl std::fmt::Debug for #report_name {
+                fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                    std::fmt::Display::fmt(&self.0.display_chain(), f)
+                }
+            }
+
+            impl From<#enum_name> for #report_name {
+                fn from(err: #enum_name) -> Self {
+                    Self(err)
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Optional wrapping of boxed dynamic errors
+    let boxed_error = match &context_attr.boxed_error {
+        Some(path) => match boxed_error_impl(enum_name, &input_enum.variants, path, &krate) {
+            Ok(tokens) => tokens,
+            Err(err) => return err.into_compile_error().into(),
+        },
+        None => quote! {},
+    };
+
+    let message_variant = match &context_attr.message_variant {
+        Some(path) => match message_variant_impl(enum_name, &input_enum.variants, path) {
+            Ok(tokens) => tokens,
+            Err(err) => return err.into_compile_error().into(),
+        },
+        None => quote! {},
+    };
+
+    // Extension trait impl for results with the given error type,
+    // which is converted into the enum by the given expression from `e`
+    let make_ext_impl = |generics: proc_macro2::TokenStream,
+                         error_ty: proc_macro2::TokenStream,
+                         bounds: proc_macro2::TokenStream,
+                         convert: proc_macro2::TokenStream| {
+        let fields_ext = if fields_enabled {
+            quote! {
+                fn with_fields<I, K, V>(self, f: impl FnOnce() -> I) -> std::result::Result<T, #enum_name>
+                where
+                    I: IntoIterator<Item = (K, V)>,
+                    K: Into<String>,
+                    V: Into<String>,
+                {
+                    self.map_err(|e| {
+                        let mut e = match #convert {
+                            e @ #enum_name::__WithContext { .. } => e,
+                            e => #enum_name::__new_context(String::new(), e),
+                        };
+                        if let #enum_name::__WithContext { fields, .. } = &mut e {
+                            fields.extend(f().into_iter().map(|(k, v)| (k.into(), v.into())));
+                        }
+                        e
+                    })
+                }
+            }
+        } else {
+            quote! {}
+        };
+
+        let severity_ext = if severity_enabled {
+            quote! {
+                fn with_context_severity<S: std::fmt::Display>(self, severity: #krate::Severity, f: impl FnOnce() -> S) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| {
+                        let mut e = #enum_name::__new_context(#krate::__into_context(f()), #convert);
+                        if let #enum_name::__WithContext { severity: s, .. } = &mut e {
+                            *s = severity;
+                        }
+                        e
+                    })
+                }
+            }
+        } else {
+            quote! {}
+        };
+
+        quote! {
+            impl<#generics T> #krate::AddErrorContext<#enum_name, T> for std::result::Result<T, #error_ty>
+            #bounds
+            {
+                fn with_context<S: std::fmt::Display>(self, f: impl FnOnce() -> S) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| #enum_name::__new_context(#krate::__into_context(f()), #convert))
+                }
+
+                fn into_target(self) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| #convert)
+                }
+
+                fn with_context_dyn(self, f: &dyn Fn() -> String) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| #enum_name::__new_context(f(), #convert))
+                }
+
+                fn with_scoped_context(self) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| {
+                        let base: #enum_name = #convert;
+                        #krate::with_context_scopes(|scopes| {
+                            scopes.iter().rev().fold(base, |inner, ctx| #enum_name::__new_context(ctx.clone(), inner))
+                        })
+                    })
+                }
+
+                fn with_context_tap<S: std::fmt::Display>(self, f: impl FnOnce() -> S, tap: impl FnOnce(&#enum_name)) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| {
+                        let err = #enum_name::__new_context(#krate::__into_context(f()), #convert);
+                        tap(&err);
+                        err
+                    })
+                }
+
+                fn with_context_args(self, f: impl FnOnce(&mut dyn FnMut(std::fmt::Arguments<'_>))) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| {
+                        let mut ctx = String::new();
+                        f(&mut |args| {
+                            let _ = std::fmt::Write::write_fmt(&mut ctx, args);
+                        });
+                        #enum_name::__new_context(ctx, #convert)
+                    })
+                }
+
+                fn context_os(self, s: impl AsRef<std::ffi::OsStr>) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| #enum_name::__new_context(s.as_ref().to_string_lossy().into_owned(), #convert))
+                }
+
+                fn context_lossy_utf8(self, bytes: impl AsRef<[u8]>) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| #enum_name::__new_context(String::from_utf8_lossy(bytes.as_ref()).into_owned(), #convert))
+                }
+
+                fn with_path_context(self, path: impl AsRef<std::path::Path>) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| {
+                        let ctx = path.as_ref().to_string_lossy().into_owned();
+                        #enum_name::__new_context(ctx, #convert)
+                    })
+                }
+
+                fn with_labeled_path_context(self, label: &str, path: impl AsRef<std::path::Path>) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| {
+                        let ctx = format!("{} {}", label, path.as_ref().display());
+                        #enum_name::__new_context(ctx, #convert)
+                    })
+                }
+
+                fn with_context_with<S: std::fmt::Display>(self, f: impl FnOnce(&#error_ty) -> S) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| {
+                        let ctx = #krate::__into_context(f(&e));
+                        #enum_name::__new_context(ctx, #convert)
+                    })
+                }
+
+                fn map_to_context(self, f: impl FnOnce(#error_ty) -> (#enum_name, String)) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| {
+                        let (base, ctx) = f(e);
+                        #enum_name::__new_context(ctx, base)
+                    })
+                }
+
+                fn context(self, msg: impl Into<String>) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| #enum_name::__new_context(msg.into(), #convert))
+                }
+
+                fn with_static_context(self, msg: &'static str) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| #enum_name::__new_context(msg, #convert))
+                }
+
+                fn with_context_if<S: std::fmt::Display>(self, cond: bool, f: impl FnOnce() -> S) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| if cond {
+                        #enum_name::__new_context(#krate::__into_context(f()), #convert)
+                    } else {
+                        #convert
+                    })
+                }
+
+                fn or_else_context<S: std::fmt::Display>(self, f: impl FnOnce() -> S) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| match #convert {
+                        e if e.has_context() => e,
+                        e => #enum_name::__new_context(#krate::__into_context(f()), e),
+                    })
+                }
+
+                fn or_context(self, msg: impl Into<String>) -> std::result::Result<T, #enum_name> {
+                    self.map_err(|e| #convert.or_context(msg))
+                }
+
+                #fields_ext
+
+                #severity_ext
+            }
+        }
+    };
+
+    // Results with boxed errors, which could be converted into the enum.
+    // A blanket impl for `Box<E>` would overlap with the main impl,
+    // so the impls are generated for the enum itself and the types of `#[from]` fields,
+    // unless the boxed type is converted by `#[from]` itself.
+    let from_types: Vec<syn::Type> = input_enum.variants.iter()
+        .flat_map(|v| v.fields.iter())
+        .filter(|f| f.attrs.iter().any(|a| a.path().is_ident("from")))
+        .map(|f| f.ty.clone())
+        .chain(context_attr.bridges.iter().map(|b| syn::parse_quote! { #b }))
+        .collect();
+    let mut boxed_types: Vec<syn::Type> = vec![syn::parse_quote! { #enum_name }];
+    for ty in &from_types {
+        // The types are compared by their tokens, since `syn::Type` is not comparable without `extra-traits`
+        let same = |a: &syn::Type, b: &syn::Type| quote!(#a).to_string() == quote!(#b).to_string();
+        let is_box = boxed_type(ty).is_some();
+        let is_known = boxed_types.iter().any(|t| same(t, ty));
+        let is_boxed_from = from_types.iter().any(|t| boxed_type(t).is_some_and(|inner| same(inner, ty)));
+        if !is_box && !is_known && !is_boxed_from {
+            boxed_types.push(ty.clone());
+        }
+    }
+
+    let separator = context_attr
+        .separator
+        .clone()
+        .unwrap_or_else(|| LitStr::new(": ", proc_macro2::Span::call_site()));
+
+    // Extension trait impls, which could be disabled by `no_ext_impl`
+    let ext_impl = if context_attr.no_ext_impl {
+        quote! {}
+    } else {
+        let main_impl = make_ext_impl(
+            quote! { E, },
+            quote! { E },
+            quote! { where E: Into<#enum_name>, },
+            quote! { e.into() },
+        );
+        let boxed_impls = boxed_types.iter().map(|ty| make_ext_impl(
+            quote! {},
+            quote! { Box<#ty> },
+            quote! {},
+            quote! { #enum_name::from(*e) },
+        ));
+        quote! {
+            #main_impl
+
+            #(#boxed_impls)*
+        }
+    };
+
+    // Without the extension trait impl the contexts are added with the constructor
+    let new_context = if context_attr.no_ext_impl {
+        quote! {
+            /// Wraps the base error into the context, used instead of the `AddErrorContext`
+            /// impl disabled by `no_ext_impl`
+            #visibility fn new_context(ctx: impl Into<String>, base: Self) -> Self {
+                Self::__new_context(ctx.into(), base)
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Generate the modified enum with the new variant
+    let output = quote! {
+        //#[derive(Error, Debug)]
+        #(#attrs)*
+        #visibility enum #enum_name {
+            #variants
+        }
+
+        impl #enum_name {
+            /// Separator of the contexts in the message returned by `full_message()`
+            #visibility const CONTEXT_SEPARATOR: &'static str = #separator;
+
+            // Wraps the error into the context variant
+            fn __new_context(ctx: impl Into<std::borrow::Cow<'static, str>>, inner: Self) -> Self {
+                let err = Self::__WithContext { context: ctx.into(), source: Box::new(inner) #extra_init };
+                #trace_event
+                #inspect_call
+                err
+            }
+
+            #inspector_impl
+
+            /// Wraps the error into the context in the same way as `with_context()` does
+            #[must_use]
+            #visibility fn with_ctx(ctx: impl Into<String>, inner: Self) -> Self {
+                Self::__new_context(ctx.into(), inner)
+            }
+
+            #new_context
+
+            #(#field_getters)*
+
+            #display_with
+            #serde_impl
+
+            #try_each
+
+            #scopes
+
+            /// Adds the context to the error. If the error already has a context,
+            /// the new one is appended as the outermost.
+            #[must_use]
+            #visibility fn attach_context(self, ctx: impl Into<String>) -> Self {
+                Self::__new_context(ctx.into(), self)
+            }
+
+            /// Adds the context only if the error doesn't have any context yet
+            #[must_use]
+            #visibility fn or_context(self, ctx: impl Into<String>) -> Self {
+                if self.has_context() {
+                    self
+                } else {
+                    Self::__new_context(ctx.into(), self)
+                }
+            }
+
+            /// Calls the closure with the outermost context if there is any and returns the error unchanged
+            #[must_use]
+            #visibility fn inspect_context(self, f: impl FnOnce(&str)) -> Self {
+                if let Some(ctx) = self.outermost_context() {
+                    f(ctx);
+                }
+                self
+            }
+
+            /// Checks if the error has at least one context
+            #visibility fn has_context(&self) -> bool {
+                matches!(self, Self::__WithContext { .. })
+            }
+
+            #[must_use]
+            #visibility fn unwrap_context(self) -> (Option<String>,Self) {
+                match self {
+                    Self::__WithContext { context: ctx, source: err, .. } => (Some(ctx.into_owned()),*err),
+                    _ => (None,self),
+                }
+            }
+
+            /// Returns all contexts starting from the outermost one including the contexts
+            /// of other annotated enums stored in the `#[from]` or `#[source]` fields of the base error
+            #visibility fn flatten_contexts(&self) -> Vec<&str> {
+                #[allow(unused_imports)]
+                use #krate::{NestedAnnotatedContexts as _, NestedOtherContexts as _};
+                let mut contexts = Vec::new();
+                let mut err = self;
+                while let Self::__WithContext { context: ctx, source: inner, .. } = err {
+                    contexts.push(ctx.as_ref());
+                    err = inner;
+                }
+                #[allow(unreachable_patterns)]
+                match err {
+                    #(#source_arms)*
+                    _ => {}
+                }
+                contexts
+            }
+
+            #report_methods
+
+            /// Returns the base error with all context layers peeled off
+            #visibility fn base_error(&self) -> &Self {
+                let mut err = self;
+                while let Self::__WithContext { source: inner, .. } = err {
+                    err = inner;
+                }
+                err
+            }
+
+            /// Returns the base error discarding all the contexts
+            #[must_use]
+            #visibility fn strip_context(self) -> Self {
+                let mut err = self;
+                while let Self::__WithContext { source: inner, .. } = err {
+                    err = *inner;
+                }
+                err
+            }
+
+            /// Returns the outermost context if there is any
+            #visibility fn outermost_context(&self) -> Option<&str> {
+                match self {
+                    Self::__WithContext { context: ctx, .. } => Some(ctx.as_ref()),
+                    _ => None,
+                }
+            }
+
+            /// Returns the innermost context if there is any
+            #visibility fn innermost_context(&self) -> Option<&str> {
+                let mut err = self;
+                let mut innermost = None;
+                while let Self::__WithContext { context: ctx, source: inner, .. } = err {
+                    innermost = Some(ctx.as_ref());
+                    err = inner;
+                }
+                innermost
+            }
+
+            /// Returns the context at the given depth starting from the outermost one at 0
+            #visibility fn context_at(&self, depth: usize) -> Option<&str> {
+                let mut err = self;
+                let mut level = 0;
+                while let Self::__WithContext { context: ctx, source: inner, .. } = err {
+                    if level == depth {
+                        return Some(ctx.as_ref());
+                    }
+                    level += 1;
+                    err = inner;
+                }
+                None
+            }
+
+            /// Checks if any context satisfies the predicate starting from the outermost one
+            #visibility fn context_matches(&self, predicate: impl Fn(&str) -> bool) -> bool {
+                let mut err = self;
+                while let Self::__WithContext { context: ctx, source: inner, .. } = err {
+                    if predicate(ctx) {
+                        return true;
+                    }
+                    err = inner;
+                }
+                false
+            }
+
+            /// Returns the outermost context or an empty string
+            #visibility fn context_str(&self) -> &str {
+                self.context_str_or("")
+            }
+
+            /// Returns the outermost context or the provided default
+            #visibility fn context_str_or<'a>(&'a self, default: &'a str) -> &'a str {
+                self.outermost_context().unwrap_or(default)
+            }
+
+            /// Displays only the outermost context or nothing if there is no context
+            #visibility fn fmt_context(&self) -> impl std::fmt::Display + '_ {
+                self.context_str()
+            }
+
+            /// Iterates over mutable references to all contexts starting from the outermost
+            #visibility fn contexts_mut(&mut self) -> impl Iterator<Item = &mut String> {
+                let mut next = Some(self);
+                std::iter::from_fn(move || match next.take()? {
+                    Self::__WithContext { context: ctx, source: inner, .. } => {
+                        next = Some(&mut **inner);
+                        Some(ctx.to_mut())
+                    }
+                    _ => None,
+                })
+            }
+
+            /// Checks if both errors are the same variant ignoring the context
+            #visibility fn variant_eq(&self, other: &Self) -> bool {
+                std::mem::discriminant(self.base_error()) == std::mem::discriminant(other.base_error())
+            }
+
+            /// Joins all context strings and the message of the base error with `CONTEXT_SEPARATOR`
+            #visibility fn full_message(&self) -> String {
+                use std::fmt::Write;
+                let mut msg = String::new();
+                let mut err = self;
+                while let Self::__WithContext { context: ctx, source: inner, .. } = err {
+                    msg.push_str(ctx);
+                    msg.push_str(Self::CONTEXT_SEPARATOR);
+                    err = inner;
+                }
+                let _ = write!(msg, "{}", err);
+                #external_message
+                msg
+            }
+
+            /// Returns all context strings followed by the message of the base error
+            #visibility fn message_chain(&self) -> Vec<String> {
+                let mut messages = Vec::new();
+                let mut err = self;
+                while let Self::__WithContext { context: ctx, source: inner, .. } = err {
+                    messages.push(ctx.to_string());
+                    err = inner;
+                }
+                messages.push(err.to_string());
+                messages
+            }
+
+            /// Displays the error with the chain of its causes
+            #visibility fn display_chain(&self) -> impl std::fmt::Display + '_ {
+                #krate::Chain(self)
+            }
+
+            /// Formats the error with the chain of its causes as a multi-line string
+            #visibility fn context_chain_display(&self) -> String {
+                self.display_chain().to_string()
+            }
+
+            #exit_code_method
+
+            #code_method
+
+            /// Compares the base errors ignoring the context
+            // The higher-ranked bound defers the check to the call site,
+            // so the enum is not required to implement `PartialEq`
+            #visibility fn eq_ignoring_context(&self, other: &Self) -> bool
+            where
+                for<'a> Self: PartialEq,
+            {
+                self.base_error() == other.base_error()
+            }
+
+            /// Clones the base error without the context
+            #[must_use]
+            #visibility fn cloned_without_context(&self) -> Self
+            where
+                for<'a> Self: Clone,
+            {
+                self.base_error().clone()
+            }
+
+            #(#predicates)*
+
+            #(#accessors)*
+
+            #fields_methods
+
+            #thread_methods
+
+            #severity_methods
+
+            #anyhow_methods
+        }
+
+        #ext_impl
+
+        impl #krate::ContextLayers for #enum_name {
+            fn push_context(self, ctx: String) -> Self {
+                Self::__new_context(ctx, self)
+            }
+
+            fn pop_context(self) -> (Option<String>, Self) {
+                self.unwrap_context()
+            }
+
+            fn flatten_contexts(&self) -> Vec<&str> {
+                #enum_name::flatten_contexts(self)
+            }
+
+            fn has_context(&self) -> bool {
+                #enum_name::has_context(self)
+            }
+
+            #layers_fields
+
+            #layers_severity
+        }
+
+        impl<T> #krate::InspectErrorContext<#enum_name> for std::result::Result<T, #enum_name> {
+            fn inspect_context(self, f: impl FnOnce(&str)) -> Self {
+                self.map_err(|e| e.inspect_context(f))
+            }
+        }
+
+        #kind
+
+        #parts
+
+        #typed_fields_impl
+
+        #external_impl
+
+        #pretty_debug
+
+        #into_io
+        #into_string
+
+        #report
+
+        #boxed_error
+
+        #message_variant
+
+        #(#bridges)*
+
+        #termination
+    };
+
+    output.into()
+}
+
+struct MatchPeeled {
+    expr: syn::Expr,
+    arms: Vec<syn::Arm>,
+}
+
+impl Parse for MatchPeeled {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let expr = syn::Expr::parse_without_eager_brace(input)?;
+        input.parse::<Token![=>]>()?;
+        let content;
+        syn::braced!(content in input);
+        let mut arms = Vec::new();
+        while !content.is_empty() {
+            arms.push(content.parse()?);
+        }
+        Ok(MatchPeeled { expr, arms })
+    }
+}
+
+// Extracts the path of the enum from the pattern like `MyError::Variant(..)`
+fn pattern_enum_path(pat: &syn::Pat) -> Option<Path> {
+    let variant_path = match pat {
+        syn::Pat::Path(p) => &p.path,
+        syn::Pat::TupleStruct(p) => &p.path,
+        syn::Pat::Struct(p) => &p.path,
+        syn::Pat::Or(p) => return p.cases.iter().find_map(pattern_enum_path),
+        syn::Pat::Paren(p) => return pattern_enum_path(&p.pat),
+        syn::Pat::Ident(p) => return p.subpat.as_ref().and_then(|(_, p)| pattern_enum_path(p)),
+        _ => return None,
+    };
+    if variant_path.segments.len() < 2 {
+        return None;
+    }
+    let mut enum_path = variant_path.clone();
+    enum_path.segments.pop();
+    enum_path.segments.pop_punct();
+    Some(enum_path)
+}
+
+/// Matches on the base error with all contexts peeled off.
+///
+/// `match_peeled!(err => { MyError::Underflow => ..., MyError::Overflow(n) if n > 1 => ..., ... })`
+/// consumes the error and performs an ordinary exhaustive match on its base variant,
+/// so no wildcard arm is needed.
+#[proc_macro]
+pub fn match_peeled(input: TokenStream) -> TokenStream {
+    let MatchPeeled { expr, arms } = parse_macro_input!(input as MatchPeeled);
+
+    let Some(enum_path) = arms.iter().find_map(|arm| pattern_enum_path(&arm.pat)) else {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "match_peeled! requires at least one arm with a path to the enum variant like `MyError::Variant`",
+        )
+        .into_compile_error()
+        .into();
+    };
+
+    // Peel all the contexts and match on the base error.
+    // The context variant is unreachable after peeling, so the match remains exhaustive
+    // over the user-defined variants.
+    let output = quote! {
+        {
+            let mut __peeled = #expr;
+            while let #enum_path::__WithContext { source: __inner, .. } = __peeled {
+                __peeled = *__inner;
+            }
+            match __peeled {
+                #(#arms)*
+                #enum_path::__WithContext { .. } => unreachable!(),
+            }
+        }
+    };
+
+    output.into()
+}
+
+/// Wraps any error returned from the function with the context.
+///
+/// `#[err_context("loading profile for {user_id}")]` takes the same arguments as `format!()`
+/// and could reference the arguments of the function by name. The function should return
+/// `Result<T, MyError>`, where `MyError` is annotated with `#[string_context]`.
+/// Works with both ordinary and `async` functions. If the runtime crate is renamed
+/// or re-exported, its path is given before the message: `#[err_context(crate_path = ::tsc, "...")]`.
+#[proc_macro_attribute]
+pub fn err_context(attr: TokenStream, item: TokenStream) -> TokenStream {
+    // The optional path of the runtime crate followed by the arguments of `format!()`
+    let parser = |input: ParseStream| -> syn::Result<(proc_macro2::TokenStream, proc_macro2::TokenStream)> {
+        let krate = if input.peek(Ident) && input.peek2(Token![=]) && input.fork().parse::<Ident>()? == "crate_path" {
+            input.parse::<Ident>()?;
+            input.parse::<Token![=]>()?;
+            let path = Path::parse_mod_style(input)?;
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+            quote! { #path }
+        } else {
+            quote! { ::thiserror_string_context }
+        };
+        Ok((krate, input.parse()?))
+    };
+    let (krate, format_args) = match syn::parse::Parser::parse(parser, attr) {
+        Ok(args) => args,
+        Err(err) => return err.into_compile_error().into(),
+    };
+    let syn::ItemFn { attrs, vis, sig, block } = parse_macro_input!(item as syn::ItemFn);
+
+    if format_args.is_empty() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "#[err_context] requires the context message like `#[err_context(\"loading {path}\")]`",
+        )
+        .into_compile_error()
+        .into();
+    }
+
+    let syn::ReturnType::Type(_, ret) = &sig.output else {
+        return syn::Error::new_spanned(&sig, "#[err_context] requires a function returning `Result`")
+            .into_compile_error()
+            .into();
+    };
+
+    // The body is evaluated first, so the arguments moved in the body
+    // could not be used in the context message.
+    let result = if sig.asyncness.is_some() {
+        quote! { async { let __result: #ret = #block; __result }.await }
+    } else {
+        quote! { (|| -> #ret { #block })() }
+    };
+
+    let output = quote! {
+        #(#attrs)*
+        #vis #sig {
+            let __result: #ret = #result;
+            #krate::AddErrorContext::with_context(__result, || format!(#format_args))
+        }
+    };
+
+    output.into()
+}
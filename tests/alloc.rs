@@ -0,0 +1,87 @@
+// Allocations made on the error path. The allocator counts the allocations per thread,
+// so the tests running in parallel don't affect each other.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use thiserror::Error;
+use thiserror_string_context::*;
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let before = ALLOCATIONS.with(|n| n.get());
+    let res = f();
+    (res, ALLOCATIONS.with(|n| n.get()) - before)
+}
+
+#[string_context("Custom context message: {0}")]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+#[test]
+fn static_context() {
+    let res: Result<(),MyError> = Err(MyError::Error1);
+    let (res, n) = count_allocations(|| res.with_static_context("Static"));
+    // Only the inner error is boxed
+    assert_eq!(n, 1);
+    assert_eq!(res.unwrap_err().outermost_context(), Some("Static"));
+
+    // Borrowed strings are copied
+    let res: Result<(),MyError> = Err(MyError::Error1);
+    let (_, n) = count_allocations(|| res.with_context(|| "Copied"));
+    assert_eq!(n, 2);
+}
+
+#[test]
+fn string_context() {
+    let (row, col) = (3, 14);
+    let (_, formatted) = count_allocations(|| format!("row {} col {}", row, col));
+
+    // The string returned by the closure is moved into the error
+    let res: Result<(),MyError> = Err(MyError::Error1);
+    let (res, n) = count_allocations(|| res.with_context(|| format!("row {} col {}", row, col)));
+    assert_eq!(n, formatted + 1);
+    assert_eq!(res.unwrap_err().outermost_context(), Some("row 3 col 14"));
+
+    let res: Result<(),MyError> = Err(MyError::Error1);
+    let (_, n) = count_allocations(|| {
+        res.with_context(|| std::borrow::Cow::<str>::Owned(format!("row {} col {}", row, col)))
+    });
+    assert_eq!(n, formatted + 1);
+}
+
+#[test]
+fn context_args() {
+    let (row, col) = (3, 14);
+    let (_, formatted) = count_allocations(|| {
+        let mut s = String::new();
+        std::fmt::Write::write_fmt(&mut s, format_args!("row {} col {}", row, col)).unwrap();
+        s
+    });
+
+    // The context is formatted directly into the stored string
+    let res: Result<(),MyError> = Err(MyError::Error1);
+    let (res, n) = count_allocations(|| res.with_context_args(|w| w(format_args!("row {} col {}", row, col))));
+    assert_eq!(n, formatted + 1);
+    assert_eq!(res.unwrap_err().outermost_context(), Some("row 3 col 14"));
+}
@@ -0,0 +1,67 @@
+//! The runtime crate is only available as `tsc` here, so the generated code
+//! has to use the path given by `crate_path`.
+
+use thiserror::Error;
+use tsc::string_context;
+
+mod reexport {
+    pub use tsc as context;
+}
+
+#[string_context("Renamed context: {0}", crate_path = ::tsc)]
+#[derive(Error,Debug)]
+pub enum RenamedError {
+    #[error("Not found")]
+    NotFound,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[string_context(crate_path = crate::reexport::context)]
+#[derive(Error,Debug)]
+pub enum ReexportedError {
+    #[error("Invalid")]
+    Invalid,
+}
+
+#[tsc::err_context(crate_path = ::tsc, "loading {id}")]
+pub fn load(id: u32) -> Result<u32, RenamedError> {
+    if id == 0 {
+        return Err(RenamedError::NotFound);
+    }
+    Ok(id)
+}
+
+#[tsc::err_context(crate_path = crate::reexport::context, "validating")]
+pub fn validate() -> Result<(), ReexportedError> {
+    Err(ReexportedError::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tsc::AddErrorContext;
+
+    #[test]
+    fn test_renamed_crate() {
+        let res: Result<(), RenamedError> = Err(RenamedError::NotFound);
+        let err = res.with_context(|| "loading").unwrap_err();
+        assert_eq!(err.to_string(), "Renamed context: loading");
+        assert_eq!(err.full_message(), "loading: Not found");
+
+        let res: Result<(), std::io::Error> = Err(std::io::ErrorKind::NotFound.into());
+        let res: Result<(), RenamedError> = res.context("reading");
+        assert!(res.unwrap_err().has_context());
+
+        let res: Result<(), ReexportedError> = Err(ReexportedError::Invalid);
+        let err = res.with_static_context("validating").unwrap_err();
+        assert_eq!(err.full_message(), "validating: Invalid");
+    }
+
+    #[test]
+    fn test_renamed_err_context() {
+        assert_eq!(load(1).unwrap(), 1);
+        assert_eq!(load(0).unwrap_err().full_message(), "loading 0: Not found");
+        assert_eq!(validate().unwrap_err().full_message(), "validating: Invalid");
+    }
+}
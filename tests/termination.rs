@@ -0,0 +1,58 @@
+// Output of `main` returning the annotated errors. The test binary runs itself as a child process
+// reporting the returned value in the same way as `main` does, and its stderr is checked.
+#![cfg(feature = "termination")]
+use std::process::{Command, ExitCode, Termination};
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context("Custom context message: {0}")]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Slight underflow happened!")]
+    #[exit_code(3)]
+    Underflow,
+}
+
+fn main_result() -> Result<(), MainError<MyError>> {
+    Err(MyError::Underflow).with_context(|| "Crashing with value 41")?;
+    Ok(())
+}
+
+fn main_exit_code() -> ExitCode {
+    match Err::<(), _>(MyError::Underflow).with_context(|| "Crashing with value 41") {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => err.report(),
+    }
+}
+
+// Reports the value returned by `main` in the child process,
+// while the parent process gets what the child printed to stderr
+fn run_main<R: Termination>(test: &str, main: fn() -> R) -> Option<String> {
+    if std::env::var_os("TERMINATION_CHILD").is_some() {
+        main().report();
+        return None;
+    }
+    let out = Command::new(std::env::current_exe().unwrap())
+        .args([test, "--exact", "--nocapture"])
+        .env("TERMINATION_CHILD", "1")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    Some(String::from_utf8(out.stderr).unwrap())
+}
+
+#[test]
+fn result_main() {
+    if let Some(stderr) = run_main("result_main", main_result) {
+        assert_eq!(stderr, "Error: Custom context message: Crashing with value 41\n\nCaused by:\n    Slight underflow happened!\n");
+        assert_eq!(main_result().report(), ExitCode::FAILURE);
+    }
+}
+
+#[test]
+fn exit_code_main() {
+    if let Some(stderr) = run_main("exit_code_main", main_exit_code) {
+        assert_eq!(stderr, "Error: Custom context message: Crashing with value 41\n\nCaused by:\n    Slight underflow happened!\n");
+        assert_eq!(main_exit_code(), ExitCode::from(3));
+    }
+}
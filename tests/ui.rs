@@ -0,0 +1,8 @@
+// Compilation errors reported by the macros are checked against the `.stderr` snapshots,
+// which are regenerated with `TRYBUILD=overwrite cargo test --test ui`
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/fail/*.rs");
+    t.pass("tests/ui/pass/*.rs");
+}
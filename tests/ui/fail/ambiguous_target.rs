@@ -0,0 +1,23 @@
+// The target of `with_context()` is ambiguous if the error converts into several enums
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context("Config: {0}")]
+#[derive(Error,Debug)]
+enum ConfigError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[string_context("Network: {0}")]
+#[derive(Error,Debug)]
+enum NetworkError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+fn main() {
+    let res: std::io::Result<()> = Err(std::io::ErrorKind::NotFound.into());
+    let err = res.with_context(|| "reading config").unwrap_err();
+    println!("{}", err);
+}
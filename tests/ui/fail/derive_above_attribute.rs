@@ -0,0 +1,12 @@
+// `thiserror::Error` must be derived below the attribute
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[derive(Error,Debug)]
+#[string_context]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {}
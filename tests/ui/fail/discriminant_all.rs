@@ -0,0 +1,15 @@
+// Explicit discriminants are not supported
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context]
+#[derive(Error,Debug)]
+#[repr(u8)]
+enum MyError {
+    #[error("Error 1")]
+    Error1 = 1,
+    #[error("Error 2")]
+    Error2 = 2,
+}
+
+fn main() {}
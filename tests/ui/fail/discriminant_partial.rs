@@ -0,0 +1,14 @@
+// Explicit discriminants are not supported
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+    #[error("Error 2")]
+    Error2 = 2,
+}
+
+fn main() {}
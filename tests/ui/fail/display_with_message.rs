@@ -0,0 +1,16 @@
+// The message conflicts with `display_with`
+use thiserror::Error;
+use thiserror_string_context::*;
+
+fn render(ctx: &str, _: &MyError, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.write_str(ctx)
+}
+
+#[string_context("Context: {0}", display_with = render)]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {}
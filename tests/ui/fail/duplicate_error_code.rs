@@ -0,0 +1,16 @@
+// Error codes must be unique
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    #[error_code("E1")]
+    Error1,
+    #[error("Error 2")]
+    #[error_code("E1")]
+    Error2,
+}
+
+fn main() {}
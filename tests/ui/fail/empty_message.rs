@@ -0,0 +1,12 @@
+// The message could not be empty
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context("")]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+// The localized message can't be combined with the literal message
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context("Context: {0}", key = "err.context", localized)]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+// The localized message requires the key
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context(localized)]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+// `match_peeled!` is exhaustive over user-defined variants
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+    #[error("Error 2")]
+    Error2,
+}
+
+fn main() {
+    let _ = match_peeled!(MyError::Error1 => {
+        MyError::Error1 => 1,
+    });
+}
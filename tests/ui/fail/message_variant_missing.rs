@@ -0,0 +1,12 @@
+// The `message_variant` must exist
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context(message_variant = Other)]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {}
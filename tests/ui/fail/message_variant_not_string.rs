@@ -0,0 +1,12 @@
+// The `message_variant` must carry a `String`
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context(message_variant = Other)]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Other {0}")]
+    Other(i32),
+}
+
+fn main() {}
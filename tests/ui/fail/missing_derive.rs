@@ -0,0 +1,11 @@
+// `thiserror::Error` must be derived below the attribute
+use thiserror_string_context::*;
+
+#[string_context]
+#[derive(Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {}
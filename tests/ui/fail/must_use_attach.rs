@@ -0,0 +1,15 @@
+// The errors returned by the methods adding or removing the context must be used
+#![deny(unused_must_use)]
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {
+    MyError::Error1.attach_context("dropped");
+}
@@ -0,0 +1,15 @@
+// `no_ext_impl` disables the `AddErrorContext` impl
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context(no_ext_impl)]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {
+    let res: Result<(), MyError> = Err(MyError::Error1);
+    let _ = AddErrorContext::with_context(res, || "Context");
+}
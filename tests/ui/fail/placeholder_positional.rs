@@ -0,0 +1,12 @@
+// Only `{0}` is allowed in the context message
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context("bad {}")]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {}
@@ -0,0 +1,23 @@
+// Generated methods have the same visibility as the enum
+mod outer {
+    pub mod inner {
+        use thiserror::Error;
+        use thiserror_string_context::*;
+
+        #[string_context]
+        #[derive(Error,Debug)]
+        pub(in crate::outer) enum MyError {
+            #[error("Error 1")]
+            Error1,
+        }
+    }
+
+    #[allow(private_interfaces)]
+    pub fn make_error() -> inner::MyError {
+        inner::MyError::Error1
+    }
+}
+
+fn main() {
+    let _ = outer::make_error().unwrap_context();
+}
@@ -0,0 +1,15 @@
+// The context string is rendered with `Debug` independently of the base variant
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context("Debug: {0:?}")]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Base {0}")]
+    Base(String),
+}
+
+fn main() {
+    let err = MyError::with_ctx("say \"hi\"", MyError::Base("x".into()));
+    assert_eq!(err.to_string(), "Debug: \"say \\\"hi\\\"\"");
+}
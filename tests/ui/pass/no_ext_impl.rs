@@ -0,0 +1,16 @@
+// The context could still be added with the constructor generated with `no_ext_impl`
+use thiserror::Error;
+use thiserror_string_context::*;
+
+#[string_context(no_ext_impl)]
+#[derive(Error,Debug)]
+enum MyError {
+    #[error("Error 1")]
+    Error1,
+}
+
+fn main() {
+    let res: Result<(), MyError> = Err(MyError::Error1);
+    let err = res.map_err(|e| MyError::new_context("Context", e)).unwrap_err();
+    assert_eq!(err.outermost_context(), Some("Context"));
+}